@@ -47,6 +47,9 @@ fn main() {
     build_slang("sample");
     build_slang("ray_extend");
     build_slang("shade");
+    build_slang("ray_connect");
+    build_slang("display");
+    build_slang("focus");
     // build_slang("logic");
     // build_slang("new_ray");
     // build_slang("extension");
@@ -1,60 +1,83 @@
 use std::{collections::HashMap, io::Read, num::NonZero};
 
 use bevy_ecs::prelude::*;
-use glam::Vec4;
 use itertools::Itertools;
 use wgpu::util::DeviceExt;
 
 use crate::{
     app::BevyApp,
-    bvh::{AABB, BVHNodeGPU},
+    bvh::{AABB, BVHNodeGPU, BVHSettings},
+    environment::Environment,
     instance::Instance,
+    light::{self, LightSource},
     material::{Material, MaterialId, MaterialServer},
     mesh::{MeshId, MeshServer},
     pathtracer::{Pathtracer, PathtracerOutput},
     render_resources::{RenderDevice, RenderQueue},
     schedule,
+    texture::{MAX_TEXTURES, TextureServer, texture_loading_system},
     tlas::TLAS,
     transform::Transform,
 };
 
 pub fn initialize(app: &mut BevyApp) {
     app.world.insert_resource(SceneBindings::default());
-    app.world
-        .get_resource_or_init::<Schedules>()
-        .add_systems(schedule::Update, binder_system);
+    app.world.get_resource_or_init::<Schedules>().add_systems(
+        schedule::Update,
+        binder_system.after(texture_loading_system),
+    );
 }
 
 #[derive(Resource, Default)]
 pub struct SceneBindings {
     pub bind_group: Option<wgpu::BindGroup>,
     pub bind_group_layout: Option<wgpu::BindGroupLayout>,
+    // Bumped whenever anything that shows up in the image changes, pathtracers restart their
+    // accumulation when they see a new one:
+    pub epoch: u32,
 }
 
 #[derive(Resource)]
 pub struct BinderLocal {
+    tlas: Option<TLAS>,
+    // The entity behind each instance the TLAS was built over, a refit needs the same ones:
+    tlas_entities: Vec<Entity>,
     tlas_cache: Option<wgpu::Buffer>,
     tlas_iids: Option<wgpu::Buffer>,
     tlas_regenerate: bool,
+    tlas_refit: bool,
+    // Emissive triangles, only gathered again when they could have moved or changed material:
+    light_sources: Vec<LightSource>,
+    // Environment pixels and cdf, only uploaded again when the environment changes:
+    environment: Option<(wgpu::Buffer, wgpu::Buffer)>,
 }
 
 impl Default for BinderLocal {
     fn default() -> Self {
         Self {
+            tlas: None,
+            tlas_entities: Vec::new(),
             tlas_cache: Default::default(),
             tlas_iids: None,
             tlas_regenerate: true,
+            tlas_refit: false,
+            light_sources: Vec::new(),
+            environment: None,
         }
     }
 }
 
 pub fn binder_system(
-    objects: Query<(Ref<Transform>, Ref<MeshId>, &MaterialId)>,
+    objects: Query<(Entity, Ref<Transform>, Ref<MeshId>, Ref<MaterialId>)>,
     removed_transforms: RemovedComponents<Transform>,
     removed_meshids: RemovedComponents<MeshId>,
     mesh_server: Res<MeshServer>,
     material_server: Res<MaterialServer>,
     device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+    bvh_settings: Res<BVHSettings>,
+    environment: Res<Environment>,
+    texture_server: Res<TextureServer>,
     mut binder_local: Local<BinderLocal>,
     mut path_tracer_bindings: ResMut<SceneBindings>,
 ) {
@@ -163,6 +186,52 @@ pub fn binder_system(
                     },
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 10,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 11,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 12,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 13,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: NonZero::new(MAX_TEXTURES),
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 14,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
             ],
         });
 
@@ -185,24 +254,29 @@ pub fn binder_system(
     let mut transforms = Vec::<Transform>::new();
     let mut instances = Vec::<Instance>::new();
     let mut materials_id_map = HashMap::<MaterialId, u32>::new();
-    let mut light_sources = Vec::<u32>::new();
+    let mut entities = Vec::<Entity>::new();
 
     if !removed_transforms.is_empty() && !removed_meshids.is_empty() {
         binder_local.tlas_regenerate = true;
     }
 
-    // TODO:
-    // let mut textures = vec![];
-    // let mut samplers = vec![];
-
-    for (transform, mesh_id, mat_id) in objects {
-        if transform.is_changed()
-            || transform.is_added()
-            || mesh_id.is_changed()
-            || mesh_id.is_added()
-            || mesh_server.is_changed()
-        {
+    let mut materials_changed = material_server.is_changed();
+    let mut scene_changed = !removed_transforms.is_empty()
+        || !removed_meshids.is_empty()
+        || mesh_server.is_changed()
+        || material_server.is_changed()
+        || texture_server.is_changed()
+        || environment.is_changed();
+
+    for (entity, transform, mesh_id, mat_id) in objects {
+        scene_changed |= transform.is_changed() || mesh_id.is_changed() || mat_id.is_changed();
+        materials_changed |= mat_id.is_changed();
+
+        if transform.is_added() || mesh_id.is_changed() || mesh_server.is_changed() {
             binder_local.tlas_regenerate = true;
+        } else if transform.is_changed() {
+            // Only moved, so the existing tree can be refitted:
+            binder_local.tlas_refit = true;
         }
 
         // Get the geometry index from the mesh server
@@ -210,17 +284,12 @@ pub fn binder_system(
             continue;
         };
 
-        let mut emissive = false;
-        let material_idx = if let Some(&idx) = materials_id_map.get(mat_id) {
+        let material_idx = if let Some(&idx) = materials_id_map.get(&mat_id) {
             idx
         } else {
             let Some(material) = material_server.get(*mat_id) else {
                 continue;
             };
-
-            if material.emissive != Vec4::ZERO || material.emissive_texture > 0 {
-                emissive = true;
-            }
             materials.push(*material);
 
             let idx = (materials.len() - 1) as u32;
@@ -237,10 +306,11 @@ pub fn binder_system(
             material_idx,
         };
         instances.push(instance);
+        entities.push(entity);
+    }
 
-        if emissive {
-            light_sources.push((instances.len() - 1) as u32);
-        }
+    if scene_changed {
+        path_tracer_bindings.epoch = path_tracer_bindings.epoch.wrapping_add(1);
     }
 
     if instances.is_empty() {
@@ -248,16 +318,59 @@ pub fn binder_system(
         return;
     }
 
-    if light_sources.is_empty() {
-        // what to do here? Could insist that all indexes are >0 i guess
-        // TODO properly support having no light sources lol
-        light_sources.push(u32::MAX);
+    // Anything skipped or despawned changes which instance is which, so refitting won't do:
+    if entities != binder_local.tlas_entities {
+        binder_local.tlas_regenerate = true;
+    }
+
+    if binder_local.tlas_regenerate || binder_local.tlas_refit || materials_changed {
+        binder_local.light_sources =
+            light::light_sources(&instances, &transforms, &materials, &mesh_server);
+    }
+
+    if binder_local.tlas_refit && !binder_local.tlas_regenerate {
+        let local = &mut *binder_local;
+        local.tlas_refit = false;
+        if let (Some(tlas), Some(node_buffer)) = (&mut local.tlas, &local.tlas_cache) {
+            let cost = tlas.refit(
+                mesh_server.aabbs(),
+                &transforms,
+                &instances,
+                &bvh_settings.tlas,
+            );
+
+            if tlas.needs_rebuild(cost, bvh_settings.tlas_rebuild_ratio) {
+                tracing::debug!(
+                    "Refitted TLAS cost {:.2} is past {:.2}, rebuilding",
+                    cost,
+                    tlas.build_cost
+                );
+                local.tlas_regenerate = true;
+            } else {
+                let nodes = tlas
+                    .nodes
+                    .iter()
+                    .map(|node| BVHNodeGPU::from(*node))
+                    .collect_vec();
+                queue
+                    .0
+                    .write_buffer(node_buffer, 0, bytemuck::cast_slice(nodes.as_slice()));
+            }
+        } else {
+            local.tlas_regenerate = true;
+        }
     }
 
     if binder_local.tlas_regenerate {
-        // Regenerate the TLAS only when transforms or meshes have changed
+        // Regenerate the TLAS only when instances or meshes have changed
         binder_local.tlas_regenerate = false;
-        let tlas = TLAS::new(mesh_server.aabbs(), &transforms, &instances);
+        binder_local.tlas_refit = false;
+        let tlas = TLAS::new(
+            mesh_server.aabbs(),
+            &transforms,
+            &instances,
+            &bvh_settings.tlas,
+        );
         let iids = tlas.instance_ids.iter().map(|i| *i as u32).collect_vec();
         let nodes = tlas
             .nodes
@@ -269,7 +382,7 @@ pub fn binder_system(
             &wgpu::util::BufferInitDescriptor {
                 label: Some("TLAS BVHNode Buffer"),
                 contents: bytemuck::cast_slice(nodes.as_slice()),
-                usage: wgpu::BufferUsages::STORAGE,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
             },
         ));
         binder_local.tlas_iids = Some(device.0.create_buffer_init(
@@ -279,8 +392,38 @@ pub fn binder_system(
                 usage: wgpu::BufferUsages::STORAGE,
             },
         ));
+        binder_local.tlas = Some(tlas);
+        binder_local.tlas_entities = entities;
     }
 
+    if environment.is_changed() || binder_local.environment.is_none() {
+        let pixels = device
+            .0
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Environment Pixel Buffer"),
+                contents: bytemuck::cast_slice(environment.pixels.as_slice()),
+                usage: wgpu::BufferUsages::STORAGE,
+            });
+        let cdf = device
+            .0
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("Environment CDF Buffer"),
+                contents: bytemuck::cast_slice(environment.cdf.as_slice()),
+                usage: wgpu::BufferUsages::STORAGE,
+            });
+        binder_local.environment = Some((pixels, cdf));
+    }
+    let Some((environment_pixels_buffer, environment_cdf_buffer)) = &binder_local.environment
+    else {
+        return;
+    };
+
+    // Every slot of the array is filled once the texture server has uploaded anything:
+    let Some(texture_sampler) = texture_server.sampler() else {
+        return;
+    };
+    let texture_views = texture_server.views().iter().collect_vec();
+
     let Some(tlas_node_buffer) = &binder_local.tlas_cache else {
         return;
     };
@@ -314,6 +457,7 @@ pub fn binder_system(
             usage: wgpu::BufferUsages::STORAGE,
         });
 
+    let light_sources = &binder_local.light_sources;
     let light_sources_buffer = device
         .0
         .create_buffer_init(&wgpu::util::BufferInitDescriptor {
@@ -322,6 +466,15 @@ pub fn binder_system(
             usage: wgpu::BufferUsages::STORAGE,
         });
 
+    // Depends on whether there are any emissive triangles too:
+    let environment_info_buffer = device
+        .0
+        .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Environment Info Buffer"),
+            contents: bytemuck::bytes_of(&environment.info(light_sources)),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+
     let bind_group = device.0.create_bind_group(&wgpu::BindGroupDescriptor {
         label: Some("Pathtracer Bindgroup Descriptor"),
         layout: &bind_group_layout,
@@ -366,6 +519,26 @@ pub fn binder_system(
                 binding: 9,
                 resource: light_sources_buffer.as_entire_binding(),
             },
+            wgpu::BindGroupEntry {
+                binding: 10,
+                resource: environment_pixels_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 11,
+                resource: environment_cdf_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 12,
+                resource: environment_info_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 13,
+                resource: wgpu::BindingResource::TextureViewArray(&texture_views),
+            },
+            wgpu::BindGroupEntry {
+                binding: 14,
+                resource: wgpu::BindingResource::Sampler(texture_sampler),
+            },
         ],
     });
 
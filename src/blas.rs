@@ -7,6 +7,7 @@ use crate::bvh::AABB;
 use crate::bvh::BVH;
 use crate::bvh::BVHNode;
 use crate::bvh::BVHNodeGPU;
+use crate::bvh::BuildMethod;
 use crate::mesh::Mesh;
 
 #[derive(Debug)]
@@ -57,10 +58,14 @@ impl BVH for BLAS {
     fn node_bounds(&self, idx: usize) -> AABB {
         self.nodes[idx].bounds
     }
+
+    fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
 }
 
 impl BLAS {
-    pub fn new(mesh: Mesh) -> BLAS {
+    pub fn new(mesh: Mesh, method: &BuildMethod) -> BLAS {
         let mut bvh = BLAS {
             nodes: vec![BVHNode {
                 is_leaf: true,
@@ -72,7 +77,13 @@ impl BLAS {
             mesh: mesh,
         };
 
-        bvh.initialize(4);
+        let cost = bvh.initialize(method);
+        tracing::debug!(
+            "Built BLAS for {} faces, {} nodes, SAH cost {:.2}",
+            bvh.mesh.faces.len(),
+            bvh.nodes.len(),
+            cost
+        );
 
         bvh
     }
@@ -1,5 +1,7 @@
+use bevy_ecs::resource::Resource;
 use glam::{UVec3, Vec3};
 use itertools::Itertools;
+use rayon::prelude::*;
 use wgpu::util::DeviceExt;
 
 #[derive(Default, Clone, Copy, Debug)]
@@ -9,12 +11,69 @@ pub struct AABB {
 }
 
 impl AABB {
+    // Contains nothing, the identity for union:
+    pub const EMPTY: AABB = AABB {
+        lb: Vec3::INFINITY,
+        ub: Vec3::NEG_INFINITY,
+    };
+
     pub fn union(&self, other: &AABB) -> AABB {
         AABB {
             lb: self.lb.min(other.lb),
             ub: self.ub.max(other.ub),
         }
     }
+
+    pub fn surface_area(&self) -> f32 {
+        let e = (self.ub - self.lb).max(Vec3::ZERO);
+        2.0 * (e.x * e.y + e.y * e.z + e.z * e.x)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SAHSettings {
+    pub bins: usize,
+    pub traversal_cost: f32,
+    pub intersection_cost: f32,
+}
+
+impl Default for SAHSettings {
+    fn default() -> Self {
+        Self {
+            bins: 16,
+            traversal_cost: 1.0,
+            intersection_cost: 2.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BuildMethod {
+    // Split at the centroid midpoint of the longest axis until a node has at most threshold
+    // elements.
+    Midpoint { threshold: usize },
+    // Binned surface area heuristic, nodes become leaves once splitting costs more than
+    // intersecting everything in them.
+    SAH(SAHSettings),
+}
+
+impl BuildMethod {
+    // The costs used to report the SAH cost of a tree, whichever way it was built.
+    pub fn sah_settings(&self) -> SAHSettings {
+        match self {
+            BuildMethod::Midpoint { .. } => SAHSettings::default(),
+            BuildMethod::SAH(settings) => *settings,
+        }
+    }
+}
+
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct BVHSettings {
+    pub blas: BuildMethod,
+    pub tlas: BuildMethod,
+    // Refitted TLASes are rebuilt once their SAH cost grows past this multiple of the cost
+    // they were built with.
+    pub tlas_rebuild_ratio: f32,
 }
 
 #[derive(Clone, Copy, Debug, Default)]
@@ -28,12 +87,6 @@ pub struct BVHNode {
     pub end: usize,
 }
 
-impl BVHNode {
-    fn bounds(&self) -> AABB {
-        self.bounds
-    }
-}
-
 pub trait BVH {
     fn elem_bounds(&self, elem: usize) -> AABB;
 
@@ -49,133 +102,352 @@ pub trait BVH {
 
     fn node_mut(&mut self, idx: usize) -> &mut BVHNode;
 
-    fn compute_node_bounds(&mut self, idx: usize) {
-        let mut node = *self.node(idx);
-        if !node.is_leaf {
-            let l = *self.node(node.left);
-            let r = *self.node(node.right);
-            node.bounds = l.bounds().union(&r.bounds());
-        } else {
-            let mut new_bounds = self.elem_bounds(node.start);
-            for i in node.start + 1..node.end {
-                new_bounds = new_bounds.union(&self.elem_bounds(i))
-            }
-            node.bounds = new_bounds;
+    fn node_count(&self) -> usize;
+
+    // Recomputes every node's bounds from its elements without changing the tree. The builder
+    // puts children after their parent, so one backwards pass sees them first.
+    fn refit(&mut self) {
+        for idx in (0..self.node_count()).rev() {
+            let node = *self.node(idx);
+            let bounds = if node.is_leaf {
+                (node.start..node.end)
+                    .map(|i| self.elem_bounds(i))
+                    .fold(AABB::EMPTY, |acc, b| acc.union(&b))
+            } else {
+                self.node_bounds(node.left)
+                    .union(&self.node_bounds(node.right))
+            };
+            self.node_mut(idx).bounds = bounds;
         }
-        *self.node_mut(idx) = node;
     }
 
-    fn subdivide(&mut self, idx: usize, threshold: usize) {
-        let node = *self.node(idx);
-        let node = if !node.is_leaf {
-            self.subdivide(node.left, threshold);
-            self.subdivide(node.right, threshold);
-            return;
-        } else {
-            // Don't subdivide if the number of circles within threshold:
-            if node.end - node.start <= threshold {
-                return;
+    // Applies a permutation with elem_swap, order[i] is the element (relative to start) that
+    // should end up at start + i.
+    fn reorder_range(&mut self, start: usize, order: &[usize]) {
+        let mut position: Vec<usize> = (0..order.len()).collect();
+        let mut elem_at: Vec<usize> = (0..order.len()).collect();
+        for (i, &elem) in order.iter().enumerate() {
+            let p = position[elem];
+            if p == i {
+                continue;
             }
+            self.elem_swap(start + i, start + p);
+            let displaced = elem_at[i];
+            elem_at[p] = displaced;
+            position[displaced] = p;
+            elem_at[i] = elem;
+            position[elem] = i;
+        }
+    }
 
-            // Compute the longest axis, on which we will split
-            let extent = node.bounds.ub - node.bounds.lb;
-            let mut axis = 0;
-            if extent.y > extent.x {
-                axis = 1
-            };
-            if extent.z > extent[axis] {
-                axis = 2
-            };
+    fn generate_skips(&mut self, idx: usize, next: usize) {
+        let node = *self.node(idx);
 
-            // Get the median circle
-            // let split = node.bounds.lb[axis] + extent[axis] / 2.0;
-            let (mut i, mut j) = (node.start, node.end - 1);
+        {
+            let mut n = node;
+            n.skip = next;
+            *self.node_mut(idx) = n;
+        }
 
-            let mut cmin = self.elem_centroid(node.start);
-            let mut cmax = cmin;
+        if !node.is_leaf {
+            self.generate_skips(node.left, node.right);
+            self.generate_skips(node.right, next);
+        }
+    }
 
-            for i in node.start + 1..node.end {
-                let c = self.elem_centroid(i);
-                cmin = cmin.min(c);
-                cmax = cmax.max(c);
+    // Expected cost of tracing a random ray through the tree, relative to the root's area.
+    fn sah_cost(&self, settings: &SAHSettings) -> f32 {
+        fn node_cost<B: BVH + ?Sized>(bvh: &B, idx: usize, settings: &SAHSettings) -> f32 {
+            let node = bvh.node(idx);
+            let area = node.bounds.surface_area();
+            if node.is_leaf {
+                area * settings.intersection_cost * (node.end - node.start) as f32
+            } else {
+                area * settings.traversal_cost
+                    + node_cost(bvh, node.left, settings)
+                    + node_cost(bvh, node.right, settings)
             }
+        }
 
-            let split = (cmin[axis] + cmax[axis]) * 0.5;
+        let root_area = self.node(0).bounds.surface_area();
+        if root_area <= 0.0 {
+            return 0.0;
+        }
+        node_cost(self, 0, settings) / root_area
+    }
 
-            while i < j {
-                if self.elem_centroid(i)[axis] < split {
-                    i += 1;
-                } else {
-                    self.elem_swap(i, j);
-                    j -= 1;
-                }
-            }
+    // Builds the tree over the root node's elements and returns its SAH cost. Expects the root
+    // to be the only node so far.
+    fn initialize(&mut self, method: &BuildMethod) -> f32 {
+        let root = *self.node(0);
+        let mut prims = (root.start..root.end)
+            .map(|elem| BuildPrim {
+                bounds: self.elem_bounds(elem),
+                centroid: self.elem_centroid(elem),
+                elem,
+            })
+            .collect_vec();
+
+        let nodes = build(&mut prims, root.start, method);
+
+        // The builder only moved prims around, put the elements in the same order:
+        let order = prims.iter().map(|p| p.elem - root.start).collect_vec();
+        self.reorder_range(root.start, &order);
+
+        *self.node_mut(0) = nodes[0];
+        for node in nodes.into_iter().skip(1) {
+            self.push_node(node);
+        }
 
-            if i == node.end || i == node.start {
-                // Either empty or one sided, so make no changes.
-                // This is probably unreachable given i use the median
-                // and a threshold, but here to be safe.
-                return;
-            }
+        self.generate_skips(0, 0);
+        self.sah_cost(&method.sah_settings())
+    }
+}
 
-            let left = BVHNode {
-                is_leaf: true,
-                bounds: Default::default(),
-                start: node.start,
-                end: i,
-                ..Default::default()
-            };
-            let right = BVHNode {
-                is_leaf: true,
-                bounds: Default::default(),
-                start: i,
-                end: node.end,
-                ..Default::default()
-            };
+// Everything the builder needs to know about an element, gathered up front so subtrees can be
+// built on other threads without touching the BVH itself.
+#[derive(Clone, Copy, Debug)]
+struct BuildPrim {
+    bounds: AABB,
+    centroid: Vec3,
+    elem: usize,
+}
 
-            let l = self.push_node(left);
-            let r = self.push_node(right);
-
-            self.compute_node_bounds(l);
-            self.compute_node_bounds(r);
-            self.subdivide(l, threshold);
-            self.subdivide(r, threshold);
-
-            BVHNode {
-                is_leaf: false,
-                bounds: Default::default(),
-                left: l,
-                right: r,
-                start: node.start,
-                end: node.end,
-                ..Default::default()
-            }
-        };
+// Ranges smaller than this are built on the current thread, handing them off costs more than it
+// saves.
+const PARALLEL_THRESHOLD: usize = 4096;
+
+// Bounds of the prims and of their centroids.
+fn prim_bounds(prims: &[BuildPrim]) -> (AABB, AABB) {
+    let add = |(b, c): (AABB, AABB), p: &BuildPrim| {
+        (
+            b.union(&p.bounds),
+            c.union(&AABB {
+                lb: p.centroid,
+                ub: p.centroid,
+            }),
+        )
+    };
+    let merge = |(b1, c1): (AABB, AABB), (b2, c2): (AABB, AABB)| (b1.union(&b2), c1.union(&c2));
+    let empty = (AABB::EMPTY, AABB::EMPTY);
+
+    if prims.len() >= PARALLEL_THRESHOLD {
+        prims.par_iter().fold(|| empty, add).reduce(|| empty, merge)
+    } else {
+        prims.iter().fold(empty, add)
+    }
+}
 
-        *self.node_mut(idx) = node;
-        self.compute_node_bounds(idx);
+// Moves every prim that goes left before the others, returning the index of the first one
+// that doesn't.
+fn partition(prims: &mut [BuildPrim], goes_left: impl Fn(&BuildPrim) -> bool + Sync) -> usize {
+    if prims.len() >= PARALLEL_THRESHOLD {
+        let (left, right): (Vec<_>, Vec<_>) = prims.par_iter().partition(|p| goes_left(p));
+        let mid = left.len();
+        prims[..mid].copy_from_slice(&left);
+        prims[mid..].copy_from_slice(&right);
+        return mid;
     }
 
-    fn generate_skips(&mut self, idx: usize, next: usize) {
-        let node = *self.node(idx);
+    let (mut i, mut j) = (0, prims.len());
+    while i < j {
+        if goes_left(&prims[i]) {
+            i += 1;
+        } else {
+            j -= 1;
+            prims.swap(i, j);
+        }
+    }
+    i
+}
 
-        {
-            let mut n = node;
-            n.skip = next;
-            *self.node_mut(idx) = n;
+fn split_midpoint(
+    prims: &mut [BuildPrim],
+    bounds: &AABB,
+    centroids: &AABB,
+    threshold: usize,
+) -> Option<usize> {
+    // Don't subdivide if the number of elements is within threshold:
+    if prims.len() <= threshold {
+        return None;
+    }
+
+    // Compute the longest axis, on which we will split
+    let extent = bounds.ub - bounds.lb;
+    let mut axis = 0;
+    if extent.y > extent.x {
+        axis = 1
+    };
+    if extent.z > extent[axis] {
+        axis = 2
+    };
+
+    let split = (centroids.lb[axis] + centroids.ub[axis]) * 0.5;
+    Some(partition(prims, |p| p.centroid[axis] < split))
+}
+
+// Per axis bounds and counts of the prims falling in each bin.
+#[derive(Clone)]
+struct Bins {
+    bounds: [Vec<AABB>; 3],
+    counts: [Vec<usize>; 3],
+}
+
+// Binned SAH as in "On fast Construction of SAH-based Bounding Volume Hierarchies"
+// (Wald 2007), trying every bin boundary on all three axes.
+fn split_sah(
+    prims: &mut [BuildPrim],
+    bounds: &AABB,
+    centroids: &AABB,
+    settings: &SAHSettings,
+) -> Option<usize> {
+    let count = prims.len();
+    if count <= 1 {
+        return None;
+    }
+
+    let bins = settings.bins.max(2);
+    let extent = centroids.ub - centroids.lb;
+    let bin_of = |c: Vec3, axis: usize| {
+        let b = ((c[axis] - centroids.lb[axis]) / extent[axis] * bins as f32) as usize;
+        b.min(bins - 1)
+    };
+
+    let empty = || Bins {
+        bounds: std::array::from_fn(|_| vec![AABB::EMPTY; bins]),
+        counts: std::array::from_fn(|_| vec![0; bins]),
+    };
+    let add = |mut acc: Bins, p: &BuildPrim| {
+        for axis in 0..3 {
+            if extent[axis] > 0.0 {
+                let b = bin_of(p.centroid, axis);
+                acc.bounds[axis][b] = acc.bounds[axis][b].union(&p.bounds);
+                acc.counts[axis][b] += 1;
+            }
+        }
+        acc
+    };
+    let merge = |mut a: Bins, b: Bins| {
+        for axis in 0..3 {
+            for i in 0..bins {
+                a.bounds[axis][i] = a.bounds[axis][i].union(&b.bounds[axis][i]);
+                a.counts[axis][i] += b.counts[axis][i];
+            }
+        }
+        a
+    };
+    let binned = if count >= PARALLEL_THRESHOLD {
+        prims.par_iter().fold(empty, add).reduce(empty, merge)
+    } else {
+        prims.iter().fold(empty(), add)
+    };
+
+    // (axis, first bin on the right, cost)
+    let mut best: Option<(usize, usize, f32)> = None;
+    for axis in 0..3 {
+        if extent[axis] <= 0.0 {
+            continue;
+        }
+        let (bin_bounds, bin_counts) = (&binned.bounds[axis], &binned.counts[axis]);
+
+        // Sweep from the right so the left sweep can cost each plane in one pass:
+        let mut right_area = vec![0.0; bins];
+        let mut right_count = vec![0usize; bins];
+        let (mut b_acc, mut n) = (AABB::EMPTY, 0);
+        for b in (1..bins).rev() {
+            b_acc = b_acc.union(&bin_bounds[b]);
+            n += bin_counts[b];
+            right_area[b] = if n > 0 { b_acc.surface_area() } else { 0.0 };
+            right_count[b] = n;
         }
 
-        if !node.is_leaf {
-            self.generate_skips(node.left, node.right);
-            self.generate_skips(node.right, next);
+        let (mut b_acc, mut n) = (AABB::EMPTY, 0);
+        for b in 1..bins {
+            b_acc = b_acc.union(&bin_bounds[b - 1]);
+            n += bin_counts[b - 1];
+            if n == 0 || right_count[b] == 0 {
+                continue;
+            }
+            let cost = n as f32 * b_acc.surface_area() + right_count[b] as f32 * right_area[b];
+            if best.is_none_or(|(_, _, c)| cost < c) {
+                best = Some((axis, b, cost));
+            }
         }
     }
 
-    fn initialize(&mut self, threshold: usize) {
-        self.compute_node_bounds(0);
-        self.subdivide(0, threshold);
-        self.generate_skips(0, 0);
+    let (axis, split, cost) = best?;
+    let area = bounds.surface_area();
+    let split_cost = if area > 0.0 {
+        settings.traversal_cost + settings.intersection_cost * cost / area
+    } else {
+        settings.traversal_cost
+    };
+    if split_cost >= settings.intersection_cost * count as f32 {
+        return None;
     }
+
+    Some(partition(prims, |p| bin_of(p.centroid, axis) < split))
+}
+
+// Builds the subtree over prims, the first of which is element `offset` of the whole BVH. Nodes
+// come back depth first with the subtree's root first and child indices local to the vec, so
+// both halves of a split can be built independently and then joined.
+fn build(prims: &mut [BuildPrim], offset: usize, method: &BuildMethod) -> Vec<BVHNode> {
+    let count = prims.len();
+    let (bounds, centroids) = prim_bounds(prims);
+    let leaf = BVHNode {
+        bounds,
+        is_leaf: true,
+        start: offset,
+        end: offset + count,
+        ..Default::default()
+    };
+
+    let split = match method {
+        BuildMethod::Midpoint { threshold } => {
+            split_midpoint(prims, &bounds, &centroids, *threshold)
+        }
+        BuildMethod::SAH(settings) => split_sah(prims, &bounds, &centroids, settings),
+    };
+    // Either empty or one sided, so make no changes:
+    let Some(mid) = split.filter(|&mid| mid > 0 && mid < count) else {
+        return vec![leaf];
+    };
+
+    let (l, r) = prims.split_at_mut(mid);
+    let (left, right) = if count >= PARALLEL_THRESHOLD {
+        rayon::join(
+            || build(l, offset, method),
+            || build(r, offset + mid, method),
+        )
+    } else {
+        (build(l, offset, method), build(r, offset + mid, method))
+    };
+
+    let shift = |by: usize| {
+        move |mut node: BVHNode| {
+            if !node.is_leaf {
+                node.left += by;
+                node.right += by;
+            }
+            node
+        }
+    };
+
+    let mut nodes = Vec::with_capacity(1 + left.len() + right.len());
+    nodes.push(BVHNode {
+        bounds,
+        is_leaf: false,
+        left: 1,
+        right: 1 + left.len(),
+        start: offset,
+        end: offset + count,
+        ..Default::default()
+    });
+    let right_shift = 1 + left.len();
+    nodes.extend(left.into_iter().map(shift(1)));
+    nodes.extend(right.into_iter().map(shift(right_shift)));
+    nodes
 }
 
 #[repr(C)]
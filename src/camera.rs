@@ -1,7 +1,9 @@
 use std::collections::{HashMap, HashSet};
 
 use bevy_ecs::prelude::*;
+use clap::ValueEnum;
 use glam::Vec2;
+use serde::{Deserialize, Serialize};
 use wgpu::util::DeviceExt;
 use winit::{event::WindowEvent, keyboard::KeyCode};
 
@@ -15,16 +17,16 @@ use crate::{
 pub fn initialize(app: &mut BevyApp) {
     app.world.get_resource_or_init::<Schedules>().add_systems(
         crate::schedule::Update,
-        (
-            camera_system.after(camera_buffer_system),
-            camera_buffer_system,
-        ),
+        (camera_system, camera_buffer_system.after(camera_system)),
     );
 }
 
-fn camera_buffer_system(cameras: Query<&mut Camera>, queue: Res<RenderQueue>) {
+// Only touches cameras that moved, so change detection on Camera means it really changed.
+pub fn camera_buffer_system(cameras: Query<&mut Camera>, queue: Res<RenderQueue>) {
     for mut camera in cameras {
-        camera.update(&queue.0);
+        if camera.changed {
+            camera.update(&queue.0);
+        }
     }
 }
 
@@ -62,6 +64,14 @@ fn camera_system(
                 let winit::keyboard::PhysicalKey::Code(key) = event.physical_key else {
                     continue;
                 };
+                // Doesn't move the camera until autofocus_system finds the distance, so
+                // leave change detection out of it:
+                if key == KeyCode::KeyF && event.state.is_pressed() && !event.repeat {
+                    camera.bypass_change_detection().autofocus = true;
+                }
+                if key == KeyCode::KeyP && event.state.is_pressed() && !event.repeat {
+                    camera.cycle_projection();
+                }
                 if event.state.is_pressed() {
                     keys_pressed.insert(key);
                 } else {
@@ -110,8 +120,14 @@ pub struct CameraData {
     pub _pad2: u32,
     pub dims: [f32; 2],
     pub focal_length: f32,
-    pub changed: u32,
-    pub _pad3: [u32; 1],
+    pub aperture: f32,
+    pub focus_distance: f32,
+    pub blades: u32,
+    pub blade_rotation: f32,
+    pub projection: u32,
+    pub ortho_height: f32,
+    pub fisheye_fov: f32,
+    pub _pad3: [u32; 2],
 }
 
 impl CameraData {
@@ -122,9 +138,121 @@ impl CameraData {
             up: [0.0, 1.0, 0.0],
             dims: [1.0, 1.0],
             focal_length: 1.0,
+            focus_distance: 1.0,
+            ortho_height: 10.0,
+            fisheye_fov: std::f32::consts::PI,
             ..Default::default()
         }
     }
+
+    pub fn projection(&self) -> Projection {
+        Projection::value_variants()[self.projection as usize]
+    }
+
+    pub fn set_projection(&mut self, settings: &ProjectionSettings) {
+        self.projection = settings.projection as u32;
+        self.ortho_height = settings.ortho_height;
+        self.fisheye_fov = settings.fisheye_fov.to_radians();
+    }
+
+    pub fn set_lens(&mut self, lens: &Lens) {
+        self.aperture = lens.aperture;
+        self.focus_distance = lens.focus_distance;
+        self.blades = lens.blades;
+        self.blade_rotation = lens.blade_rotation.to_radians();
+    }
+
+    pub fn set_view(
+        &mut self,
+        position: glam::Vec3,
+        forward: glam::Vec3,
+        up: glam::Vec3,
+        focal_length: f32,
+    ) {
+        // Keep up perpendicular to forward so translate/rotate get an orthonormal basis:
+        let forward = forward.normalize();
+        let right = up.cross(forward).normalize();
+        let up = forward.cross(right);
+
+        self.position = position.to_array();
+        self.forward = forward.to_array();
+        self.up = up.to_array();
+        self.focal_length = focal_length;
+    }
+
+    // Stretches the image plane horizontally to match a width / height aspect ratio.
+    pub fn set_aspect(&mut self, aspect: f32) {
+        self.dims = [aspect * self.dims[1], self.dims[1]];
+    }
+}
+
+// A thin lens in front of the pinhole. Things focus_distance along the view direction are
+// sharp and the rest blurs more the wider the aperture, so an aperture of 0 is a pinhole.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct Lens {
+    // Radius of the aperture in world units:
+    pub aperture: f32,
+    pub focus_distance: f32,
+    // Polygonal bokeh with this many blades, under 3 is a round aperture:
+    pub blades: u32,
+    // Degrees:
+    pub blade_rotation: f32,
+}
+
+impl Default for Lens {
+    fn default() -> Self {
+        Self {
+            aperture: 0.0,
+            focus_distance: 1.0,
+            blades: 0,
+            blade_rotation: 0.0,
+        }
+    }
+}
+
+// In the order of Projection in common.slang.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Projection {
+    /// Pinhole, or a thin lens with an aperture
+    #[default]
+    Perspective,
+    /// Parallel rays, ortho_height across the view
+    Orthographic,
+    /// Equidistant fisheye over fisheye_fov, in a circle fitting the image
+    Fisheye,
+    /// 360 degree panorama, best at a 2:1 aspect ratio
+    Equirectangular,
+}
+
+impl Projection {
+    fn next(self) -> Self {
+        let variants = Self::value_variants();
+        let i = variants.iter().position(|p| *p == self).unwrap();
+        variants[(i + 1) % variants.len()]
+    }
+}
+
+// How rays leave the camera. Only perspective and orthographic use the lens.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct ProjectionSettings {
+    pub projection: Projection,
+    // World units from the bottom to the top of the orthographic view:
+    pub ortho_height: f32,
+    // Degrees across the fisheye's image circle:
+    pub fisheye_fov: f32,
+}
+
+impl Default for ProjectionSettings {
+    fn default() -> Self {
+        Self {
+            projection: Projection::Perspective,
+            ortho_height: 10.0,
+            fisheye_fov: 180.0,
+        }
+    }
 }
 
 #[derive(Component)]
@@ -134,6 +262,8 @@ pub struct Camera {
     pub bind_group: wgpu::BindGroup,
     pub bind_group_layout: wgpu::BindGroupLayout,
     pub changed: bool,
+    // Focus on whatever is under the centre of the screen next frame, see autofocus_system.
+    pub autofocus: bool,
 }
 
 impl Camera {
@@ -146,7 +276,6 @@ impl Camera {
         //     up: [0.31, 0.86, 0.38],
         //     dims: [1.0, 1.0],
         //     focal_length: 1.0,
-        //     changed: 0,
         //     ..Default::default()
         // };
 
@@ -187,6 +316,7 @@ impl Camera {
             bind_group,
             bind_group_layout,
             changed: false,
+            autofocus: false,
         }
     }
 
@@ -194,10 +324,47 @@ impl Camera {
         if self.changed {
             queue.write_buffer(&self.uniform, 0, bytemuck::bytes_of(&self.data));
             queue.submit([]);
-            self.data.changed = 0;
+            self.changed = false;
         }
     }
 
+    pub fn set_view(
+        &mut self,
+        position: glam::Vec3,
+        forward: glam::Vec3,
+        up: glam::Vec3,
+        focal_length: f32,
+    ) {
+        self.data.set_view(position, forward, up, focal_length);
+        self.changed = true;
+    }
+
+    pub fn set_aspect(&mut self, aspect: f32) {
+        self.data.set_aspect(aspect);
+        self.changed = true;
+    }
+
+    pub fn set_lens(&mut self, lens: &Lens) {
+        self.data.set_lens(lens);
+        self.changed = true;
+    }
+
+    pub fn set_projection(&mut self, settings: &ProjectionSettings) {
+        self.data.set_projection(settings);
+        self.changed = true;
+    }
+
+    pub fn cycle_projection(&mut self) {
+        self.data.projection = self.data.projection().next() as u32;
+        self.changed = true;
+        tracing::info!("Projection: {:?}", self.data.projection());
+    }
+
+    pub fn set_focus_distance(&mut self, focus_distance: f32) {
+        self.data.focus_distance = focus_distance;
+        self.changed = true;
+    }
+
     pub fn translate(&mut self, dir: impl Into<glam::Vec3>) {
         let dir = dir.into();
         let f = glam::Vec3::from(self.data.forward);
@@ -210,7 +377,6 @@ impl Camera {
         pos += dir.z * f;
 
         self.data.position = pos.to_array();
-        self.data.changed = 1;
         self.changed = true;
     }
 
@@ -234,7 +400,6 @@ impl Camera {
         self.data.forward = f.into();
         self.data.up = u.into();
 
-        self.data.changed = 1;
         self.changed = true;
     }
 }
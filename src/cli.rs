@@ -0,0 +1,354 @@
+use std::path::{Path, PathBuf};
+
+use bevy_ecs::prelude::*;
+use clap::{Parser, ValueEnum};
+
+use crate::{
+    bvh::{BVHSettings, BuildMethod, SAHSettings},
+    camera::{Lens, Projection, ProjectionSettings},
+    display::Tonemapper,
+    pathtracer::Crop,
+    pathtracer_state::TileOrder,
+    scene_file::RenderSettings,
+    sky::Sky,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Mode {
+    /// Open a window and render progressively with camera controls
+    #[default]
+    Interactive,
+    /// Render offline until the sample target is reached, then write the output
+    Headless,
+    /// Render the sample target with the reference path tracer on the CPU, then write the
+    /// output. Needs no GPU
+    Cpu,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum BvhBuild {
+    /// Split at the centroid midpoint of the longest axis
+    Midpoint,
+    /// Binned surface area heuristic
+    #[default]
+    Sah,
+}
+
+#[derive(Parser, Resource, Clone, Debug)]
+#[command(version, about = "A wavefront pathtracer")]
+pub struct Args {
+    /// JSON scene file or glTF scene to render, or an OBJ to place in the built in scene instead
+    /// of the dragon
+    #[arg(short, long)]
+    pub scene: Option<PathBuf>,
+
+    /// Output width in pixels [default: 512]
+    #[arg(long)]
+    pub width: Option<u32>,
+
+    /// Output height in pixels [default: 512]
+    #[arg(long)]
+    pub height: Option<u32>,
+
+    /// Number of paths in flight in the wavefront, defaults to one per pixel
+    #[arg(short, long)]
+    pub threads: Option<u32>,
+
+    /// Samples per pixel to take before a headless render finishes, the most any pixel gets
+    /// with adaptive sampling [default: 1024]
+    #[arg(long)]
+    pub samples: Option<u32>,
+
+    /// Maximum number of bounces a path can take [default: 128]
+    #[arg(long)]
+    pub max_bounces: Option<u32>,
+
+    /// Where headless renders are written to. An .exr or .hdr file gets the mean radiance of each
+    /// pixel before exposure and tonemapping
+    #[arg(short, long, default_value = "render.png")]
+    pub output: PathBuf,
+
+    /// Stop sampling pixels once the relative standard error of their luminance is under this,
+    /// e.g. 0.01. Every pixel is sampled evenly if not given
+    #[arg(long)]
+    pub adaptive_threshold: Option<f32>,
+
+    /// Samples a pixel takes before adaptive sampling can stop it [default: 64]
+    #[arg(long)]
+    pub min_samples: Option<u32>,
+
+    /// Width and height of the tiles pixels are handed out in [default: 128]
+    #[arg(long)]
+    pub tile_size: Option<u32>,
+
+    /// Order the tiles are sampled in [default: shuffled]
+    #[arg(long, value_enum)]
+    pub tile_order: Option<TileOrder>,
+
+    /// Only sample this window of the frame, as x,y,width,height in pixels from the top left
+    #[arg(long)]
+    pub crop: Option<Crop>,
+
+    /// Write just the crop rather than the full frame with everything outside it left black
+    #[arg(long)]
+    pub cropped_output: bool,
+
+    /// Write the full frame from this earlier render with the crop rendered over it
+    #[arg(long)]
+    pub merge_into: Option<PathBuf>,
+
+    /// Seed for the per path random state, random if not given
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    #[arg(long, value_enum, default_value_t = Mode::Interactive)]
+    pub mode: Mode,
+
+    /// Equirectangular .hdr or .exr image lighting the scene, a constant sky if not given
+    #[arg(long)]
+    pub environment: Option<PathBuf>,
+
+    /// Rotation of the environment map about the up axis in degrees [default: 0]
+    #[arg(long)]
+    pub environment_rotation: Option<f32>,
+
+    /// Multiplier on the environment map's radiance [default: 1]
+    #[arg(long)]
+    pub environment_intensity: Option<f32>,
+
+    /// Light the scene with a physical sun and sky when there is no environment map, rather
+    /// than a constant sky
+    #[arg(long)]
+    pub sky: bool,
+
+    /// Height of the sun above the horizon in degrees, implies --sky [default: 45]
+    #[arg(long, allow_hyphen_values = true)]
+    pub sun_elevation: Option<f32>,
+
+    /// Direction of the sun about the up axis in degrees, implies --sky [default: 90]
+    #[arg(long, allow_hyphen_values = true)]
+    pub sun_azimuth: Option<f32>,
+
+    /// Haziness of the sky, from 2 for clear to 10, implies --sky [default: 3]
+    #[arg(long)]
+    pub turbidity: Option<f32>,
+
+    /// How much light the grey ground below the horizon reflects, implies --sky [default: 0.3]
+    #[arg(long)]
+    pub ground_albedo: Option<f32>,
+
+    /// How radiance is mapped to displayed colour, can be cycled with T [default: aces]
+    #[arg(long, value_enum)]
+    pub tonemapper: Option<Tonemapper>,
+
+    /// Exposure in EV, added to the automatic exposure when it's on. Stepped with [ and ]
+    /// [default: -2.5, or 0 with --auto-exposure]
+    #[arg(long, allow_hyphen_values = true)]
+    pub exposure: Option<f32>,
+
+    /// Expose the average brightness of the image to middle grey, toggled with E
+    #[arg(long)]
+    pub auto_exposure: bool,
+
+    /// Radiance the reinhard tonemapper maps to white, after exposure [default: 4]
+    #[arg(long)]
+    pub white_point: Option<f32>,
+
+    /// How rays leave the camera, can be cycled with P [default: perspective]
+    #[arg(long, value_enum)]
+    pub projection: Option<Projection>,
+
+    /// Height of the orthographic view in world units [default: 10]
+    #[arg(long)]
+    pub ortho_height: Option<f32>,
+
+    /// Field of view across the fisheye's image circle in degrees [default: 180]
+    #[arg(long)]
+    pub fisheye_fov: Option<f32>,
+
+    /// Radius of the camera lens, 0 for a pinhole with everything in focus [default: 0]
+    #[arg(long)]
+    pub aperture: Option<f32>,
+
+    /// Distance along the view direction that is in focus [default: 1]
+    #[arg(long)]
+    pub focus_distance: Option<f32>,
+
+    /// Focus on whatever is in the centre of the image before rendering, overriding
+    /// --focus-distance. Press F to do it again in the window
+    #[arg(long)]
+    pub autofocus: bool,
+
+    /// Number of aperture blades for polygonal bokeh, under 3 is a round aperture [default: 0]
+    #[arg(long)]
+    pub blades: Option<u32>,
+
+    /// Rotation of the aperture blades in degrees [default: 0]
+    #[arg(long, allow_hyphen_values = true)]
+    pub blade_rotation: Option<f32>,
+
+    /// How the BVH over each mesh's triangles is built
+    #[arg(long, value_enum, default_value_t = BvhBuild::Sah)]
+    pub blas_build: BvhBuild,
+
+    /// How the BVH over instances is built
+    #[arg(long, value_enum, default_value_t = BvhBuild::Sah)]
+    pub tlas_build: BvhBuild,
+
+    /// Number of candidate split planes per axis for SAH builds
+    #[arg(long, default_value_t = SAHSettings::default().bins)]
+    pub sah_bins: usize,
+
+    /// SAH cost of traversing a BVH node
+    #[arg(long, default_value_t = SAHSettings::default().traversal_cost)]
+    pub sah_traversal_cost: f32,
+
+    /// SAH cost of intersecting a triangle or instance
+    #[arg(long, default_value_t = SAHSettings::default().intersection_cost)]
+    pub sah_intersection_cost: f32,
+
+    /// Moving instances refit the TLAS until its SAH cost grows past this multiple of its
+    /// cost when built, then it is rebuilt. 1 rebuilds every time
+    #[arg(long, default_value_t = 1.5)]
+    pub tlas_rebuild_ratio: f32,
+}
+
+// Settings left unset fall back to the scene file, then to these defaults.
+impl Args {
+    pub fn width(&self) -> u32 {
+        self.width.unwrap_or(512)
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height.unwrap_or(512)
+    }
+
+    pub fn threads(&self) -> u32 {
+        self.threads.unwrap_or(self.width() * self.height())
+    }
+
+    pub fn samples(&self) -> u32 {
+        self.samples.unwrap_or(1024)
+    }
+
+    pub fn max_bounces(&self) -> u32 {
+        self.max_bounces.unwrap_or(128)
+    }
+
+    pub fn min_samples(&self) -> u32 {
+        self.min_samples.unwrap_or(64)
+    }
+
+    pub fn tile_size(&self) -> u32 {
+        self.tile_size.unwrap_or(128)
+    }
+
+    pub fn tile_order(&self) -> TileOrder {
+        self.tile_order.unwrap_or_default()
+    }
+
+    pub fn tonemapper(&self) -> Tonemapper {
+        self.tonemapper.unwrap_or_default()
+    }
+
+    pub fn exposure(&self) -> f32 {
+        let default = if self.auto_exposure { 0.0 } else { -2.5 };
+        self.exposure.unwrap_or(default)
+    }
+
+    pub fn white_point(&self) -> f32 {
+        self.white_point.unwrap_or(4.0)
+    }
+
+    // The scene's lens with anything given on the command line swapped in.
+    pub fn lens(&self, scene: Lens) -> Lens {
+        Lens {
+            aperture: self.aperture.unwrap_or(scene.aperture),
+            focus_distance: self.focus_distance.unwrap_or(scene.focus_distance),
+            blades: self.blades.unwrap_or(scene.blades),
+            blade_rotation: self.blade_rotation.unwrap_or(scene.blade_rotation),
+        }
+    }
+
+    // The scene's projection with anything given on the command line swapped in.
+    pub fn projection(&self, scene: ProjectionSettings) -> ProjectionSettings {
+        ProjectionSettings {
+            projection: self.projection.unwrap_or(scene.projection),
+            ortho_height: self.ortho_height.unwrap_or(scene.ortho_height),
+            fisheye_fov: self.fisheye_fov.unwrap_or(scene.fisheye_fov),
+        }
+    }
+
+    // The sun and sky, if any of it was asked for.
+    pub fn sky(&self) -> Option<Sky> {
+        let given = self.sky
+            || self.sun_elevation.is_some()
+            || self.sun_azimuth.is_some()
+            || self.turbidity.is_some()
+            || self.ground_albedo.is_some();
+        let default = Sky::default();
+        given.then(|| Sky {
+            sun_elevation: self.sun_elevation.unwrap_or(default.sun_elevation),
+            sun_azimuth: self.sun_azimuth.unwrap_or(default.sun_azimuth),
+            turbidity: self.turbidity.unwrap_or(default.turbidity),
+            ground_albedo: self.ground_albedo.unwrap_or(default.ground_albedo),
+        })
+    }
+
+    pub fn bvh_settings(&self) -> BVHSettings {
+        let sah = SAHSettings {
+            bins: self.sah_bins,
+            traversal_cost: self.sah_traversal_cost,
+            intersection_cost: self.sah_intersection_cost,
+        };
+        // Midpoint thresholds are the leaf sizes the builder has always used:
+        let method = |build, threshold| match build {
+            BvhBuild::Midpoint => BuildMethod::Midpoint { threshold },
+            BvhBuild::Sah => BuildMethod::SAH(sah),
+        };
+
+        BVHSettings {
+            blas: method(self.blas_build, 4),
+            tlas: method(self.tlas_build, 1),
+            tlas_rebuild_ratio: self.tlas_rebuild_ratio,
+        }
+    }
+
+    // Fills in anything not given on the command line from a scene file.
+    pub fn apply_settings(&mut self, settings: &RenderSettings) {
+        self.width = self.width.or(settings.width);
+        self.height = self.height.or(settings.height);
+        self.threads = self.threads.or(settings.threads);
+        self.samples = self.samples.or(settings.samples);
+        self.max_bounces = self.max_bounces.or(settings.max_bounces);
+        self.seed = self.seed.or(settings.seed);
+        self.adaptive_threshold = self.adaptive_threshold.or(settings.adaptive_threshold);
+        self.min_samples = self.min_samples.or(settings.min_samples);
+        self.tile_size = self.tile_size.or(settings.tile_size);
+        self.tile_order = self.tile_order.or(settings.tile_order);
+        self.crop = self.crop.or(settings.crop);
+        self.environment = self.environment.take().or(settings.environment.clone());
+        self.environment_rotation = self.environment_rotation.or(settings.environment_rotation);
+        self.environment_intensity = self
+            .environment_intensity
+            .or(settings.environment_intensity);
+        self.tonemapper = self.tonemapper.or(settings.tonemapper);
+        self.exposure = self.exposure.or(settings.exposure);
+        self.auto_exposure |= settings.auto_exposure;
+        self.white_point = self.white_point.or(settings.white_point);
+        if let Some(sky) = settings.sky {
+            self.sky = true;
+            self.sun_elevation = self.sun_elevation.or(Some(sky.sun_elevation));
+            self.sun_azimuth = self.sun_azimuth.or(Some(sky.sun_azimuth));
+            self.turbidity = self.turbidity.or(Some(sky.turbidity));
+            self.ground_albedo = self.ground_albedo.or(Some(sky.ground_albedo));
+        }
+    }
+}
+
+// Whether a render written to path should keep the full radiance rather than be tonemapped.
+pub fn is_hdr_output(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("exr") || e.eq_ignore_ascii_case("hdr"))
+}
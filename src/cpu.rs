@@ -0,0 +1,1312 @@
+use std::{collections::HashMap, f32::consts::PI, ops::ControlFlow, path::PathBuf, time::Instant};
+
+use bevy_ecs::prelude::*;
+use glam::{Mat4, Vec2, Vec3, Vec4, Vec4Swizzles};
+use itertools::Itertools;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use rayon::prelude::*;
+
+use crate::{
+    app::BevyApp,
+    bvh::{AABBGPU, BVHNodeGPU, BVHSettings},
+    camera::{CameraData, Projection},
+    cli::{Args, is_hdr_output},
+    display::DisplaySettings,
+    environment::Environment,
+    instance::Instance,
+    light::{self, LightSource},
+    material::{Material, MaterialId, MaterialServer},
+    mesh::{MeshData, MeshId, MeshServer},
+    pathtracer::{Pathtracer, save_render},
+    scene_file::SceneFile,
+    texture::TextureServer,
+    threadpool::ThreadPool,
+    tlas::TLAS,
+    transform::Transform,
+};
+
+// Stands in for "no previous hit" when excluding the last triangle from a trace.
+const NO_HIT: u32 = u32::MAX;
+
+// Where connect rays stop short of the light, see ray_connect.slang.
+const CONNECT_END: f32 = 1.0 - 1e-3;
+
+// How far connect rays to the environment reach, past anything in the scene.
+const ENVIRONMENT_DISTANCE: f32 = 1e6;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Ray {
+    pub pos: Vec3,
+    pub dir: Vec3,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Hit {
+    pub position: Vec3,
+    pub normal: Vec3,
+    // Interpolated texture coordinates:
+    pub uv: Vec2,
+    // Interpolated tangent, with the bitangent's handedness in w:
+    pub tangent: Vec4,
+    pub t: f32,
+    pub triangle_id: u32,
+    pub instance_id: u32,
+    pub front_face: bool,
+}
+
+// The same scene the binder hands to the gpu, traced on the cpu with the traversal from
+// ray_extend.slang and the materials from shade.slang. Used as ground truth for the gpu
+// integrator and as a fallback when there is no gpu.
+pub struct CpuScene<'a> {
+    geometries: Vec<&'a MeshData>,
+    instances: Vec<Instance>,
+    // Transform matrix and its inverse, indexed like the transforms buffer:
+    matrices: Vec<(Mat4, Mat4)>,
+    materials: Vec<Material>,
+    tlas_nodes: Vec<BVHNodeGPU>,
+    tlas_to_instances: Vec<u32>,
+    lights: Vec<LightSource>,
+    environment: &'a Environment,
+    textures: &'a TextureServer,
+    // Chance of sample_light picking the environment over a triangle:
+    environment_prob: f32,
+}
+
+impl<'a> CpuScene<'a> {
+    // Gathers every instance in the world the same way the binder does, or None if there is
+    // nothing loaded to render yet.
+    pub fn from_world(world: &'a mut World) -> Option<Self> {
+        let mut query = world.query::<(&Transform, &MeshId, &MaterialId)>();
+        let world: &'a World = world;
+        let mesh_server = world.resource::<MeshServer>();
+        let material_server = world.resource::<MaterialServer>();
+        let bvh_settings = world.resource::<BVHSettings>();
+        let environment = world.resource::<Environment>();
+        let textures = world.resource::<TextureServer>();
+
+        let mut materials = Vec::<Material>::new();
+        let mut transforms = Vec::<Transform>::new();
+        let mut instances = Vec::<Instance>::new();
+        let mut materials_id_map = HashMap::<MaterialId, u32>::new();
+
+        for (transform, mesh_id, mat_id) in query.iter(world) {
+            let Some(geometry_idx) = mesh_server.geom_id(*mesh_id) else {
+                continue;
+            };
+
+            let material_idx = if let Some(&idx) = materials_id_map.get(mat_id) {
+                idx
+            } else {
+                let Some(material) = material_server.get(*mat_id) else {
+                    continue;
+                };
+                materials.push(*material);
+
+                let idx = (materials.len() - 1) as u32;
+                materials_id_map.insert(*mat_id, idx);
+                idx
+            };
+
+            transforms.push(*transform);
+            instances.push(Instance {
+                transform_idx: (transforms.len() - 1) as u32,
+                geometry_idx,
+                material_idx,
+            });
+        }
+
+        if instances.is_empty() {
+            return None;
+        }
+
+        let lights = light::light_sources(&instances, &transforms, &materials, mesh_server);
+        let tlas = TLAS::new(
+            mesh_server.aabbs(),
+            &transforms,
+            &instances,
+            &bvh_settings.tlas,
+        );
+
+        Some(Self {
+            geometries: mesh_server.geometries().collect_vec(),
+            instances,
+            matrices: transforms
+                .iter()
+                .map(|t| {
+                    let m = t.matrix();
+                    (m, m.inverse())
+                })
+                .collect_vec(),
+            materials,
+            tlas_nodes: tlas.nodes.into_iter().map(BVHNodeGPU::from).collect_vec(),
+            tlas_to_instances: tlas.instance_ids.iter().map(|i| *i as u32).collect_vec(),
+            environment_prob: environment.light_prob(&lights),
+            lights,
+            environment,
+            textures,
+        })
+    }
+
+    // Mean radiance of every pixel, row by row, before any tonemapping.
+    pub fn render(&self, camera: &CameraData, pathtracer: &Pathtracer) -> Vec<Vec3> {
+        let (width, height) = pathtracer.dims;
+        let seed = pathtracer.seed.unwrap_or_else(rand::random);
+        // Mirrored like the gpu's sample sources, to_image flips it back:
+        let region = pathtracer.region().mirrored(width);
+
+        (0..width * height)
+            .into_par_iter()
+            .map(|idx| {
+                // Every pixel gets its own stream so the result doesn't depend on scheduling:
+                let mut rng =
+                    StdRng::seed_from_u64(seed ^ (idx as u64).wrapping_mul(0x9E3779B97F4A7C15));
+                let (x, y) = (idx % width, idx / width);
+                if !region.contains(x, y) {
+                    return Vec3::ZERO;
+                }
+
+                let mut sum = Vec3::ZERO;
+                let mut taken = 0;
+                let mut welford = Welford::default();
+                for _ in 0..pathtracer.samples_per_pixel {
+                    // Outside the fisheye's image circle is black:
+                    let rad = match camera_ray(camera, (width, height), (x, y), &mut rng) {
+                        Some(ray) => self.sample(ray, pathtracer.max_bounces, &mut rng),
+                        None => Vec3::ZERO,
+                    };
+                    // Same as the gpu, a single bad sample shouldn't ruin the pixel:
+                    let rad = Vec3::select(rad.is_finite_mask(), rad, Vec3::ZERO);
+                    sum += rad;
+                    taken += 1;
+
+                    // Same as sampleConverge, but checked after every sample:
+                    let Some(threshold) = pathtracer.adaptive_threshold else {
+                        continue;
+                    };
+                    welford.add(rad.dot(Vec3::new(0.2126, 0.7152, 0.0722)));
+                    if welford.n >= pathtracer.min_samples.max(2)
+                        && welford.relative_error() < threshold
+                    {
+                        break;
+                    }
+                }
+                sum / taken.max(1) as f32
+            })
+            .collect()
+    }
+
+    // One path, shaded like shadeMain in shade.slang.
+    fn sample(&self, mut ray: Ray, max_bounces: u32, rng: &mut StdRng) -> Vec3 {
+        let mut rad = Vec3::ZERO;
+        let mut throughput = Vec3::ONE;
+        let mut last = (NO_HIT, NO_HIT);
+        // Bsdf pdf of the bounce that led to the current hit:
+        let mut pdf = 0.0;
+
+        for bounce in 0..max_bounces {
+            let Some(h) = self.first_hit(&ray, last.0, last.1) else {
+                let le = self.environment.radiance(ray.dir);
+                // Weighted against having sampled the environment from the previous hit:
+                let weight = if bounce == 0 {
+                    1.0
+                } else {
+                    power_heuristic(pdf, self.environment_prob * self.environment.pdf(ray.dir))
+                };
+                rad += throughput * le * weight;
+                break;
+            };
+            last = (h.instance_id, h.triangle_id);
+
+            let instance = self.instances[h.instance_id as usize];
+            let mat = self.materials[instance.material_idx as usize];
+            let ms = self.material_sample(&mat, h.uv);
+            let le = ms.emissive.xyz();
+            if le.max_element() > 0.0 {
+                // Weighted against having sampled this light from the previous hit, which picks
+                // lights by their untextured power:
+                let weight = if bounce == 0 {
+                    1.0
+                } else {
+                    let light_pdf =
+                        self.light_pdf(mat.emissive.xyz(), h.position - ray.pos, h.normal);
+                    power_heuristic(pdf, light_pdf)
+                };
+                rad += throughput * le * weight;
+            }
+
+            let wo = -ray.dir;
+            let n = self.normal_map(&mat, h.uv, h.normal.normalize(), h.tangent);
+            let n = towards_view(if h.front_face { n } else { -n }, wo);
+
+            rad += self.sample_light(&h, wo, n, &ms, rng) * throughput;
+
+            let wi = bsdf_sample(wo, n, &ms, rng);
+            pdf = bsdf_pdf(wi, wo, n, &ms);
+            if pdf <= 0.0 {
+                break;
+            }
+
+            ray = Ray {
+                pos: h.position,
+                dir: wi,
+            };
+            throughput *= material(wi, wo, n, &ms) * n.dot(wi).abs() / pdf;
+        }
+
+        rad
+    }
+
+    // Light from one emissive triangle picked by power, if nothing is in the way. See sampleLight
+    // in shade.slang and ray_connect.slang.
+    fn sample_light(&self, h: &Hit, wo: Vec3, n: Vec3, ms: &Material, rng: &mut StdRng) -> Vec3 {
+        let sampled = if rng.random::<f32>() < self.environment_prob {
+            let (wi, pdf) = self.environment.sample(rng.random());
+            let connect = Ray {
+                pos: h.position,
+                dir: wi * ENVIRONMENT_DISTANCE,
+            };
+            Some((
+                connect,
+                wi,
+                pdf * self.environment_prob,
+                self.environment.radiance(wi),
+            ))
+        } else {
+            self.sample_triangle(h.position, rng)
+        };
+        let Some((connect, wi, pdf, le)) = sampled else {
+            return Vec3::ZERO;
+        };
+
+        let cos_surface = n.dot(wi);
+        if cos_surface <= 0.0 || pdf <= 0.0 {
+            return Vec3::ZERO;
+        }
+        if self
+            .trace(&connect, h.instance_id, h.triangle_id, CONNECT_END, true)
+            .is_some()
+        {
+            return Vec3::ZERO;
+        }
+
+        let weight = power_heuristic(pdf, bsdf_pdf(wi, wo, n, ms));
+        material(wi, wo, n, ms) * cos_surface * le * weight / pdf
+    }
+
+    // A point on an emissive triangle picked by power, as the connect ray to it, the direction
+    // and solid angle pdf of it, and the radiance it gives off.
+    fn sample_triangle(&self, pos: Vec3, rng: &mut StdRng) -> Option<(Ray, Vec3, f32, Vec3)> {
+        let light = light::pick_light(&self.lights, rng.random());
+        if light.instance == LightSource::NONE.instance {
+            return None;
+        }
+
+        let instance = self.instances[light.instance as usize];
+        let mesh = &self.geometries[instance.geometry_idx as usize].mesh;
+        let (m, _) = self.matrices[instance.transform_idx as usize];
+        let face = mesh.faces[light.triangle as usize];
+        let [p0, p1, p2] =
+            [face.x, face.y, face.z].map(|v| m.transform_point3(mesh.positions[v as usize].xyz()));
+
+        let (b1, b2) = light::triangle_sample(rng.random(), rng.random());
+        let p = p0 + (p1 - p0) * b1 + (p2 - p0) * b2;
+        let [t0, t1, t2] =
+            [face.x, face.y, face.z].map(|v| mesh.uvs.get(v as usize).copied().unwrap_or_default());
+        let uv = t0 * (1.0 - b1 - b2) + t1 * b1 + t2 * b2;
+
+        let c = (p1 - p0).cross(p2 - p0);
+        let area = c.length() / 2.0;
+        let d = p - pos;
+        let dist2 = d.length_squared();
+        let wi = d / dist2.sqrt();
+        let cos_light = c.normalize().dot(wi).abs();
+        if cos_light <= 0.0 || area <= 0.0 {
+            return None;
+        }
+
+        // Left unnormalised so the light sits at t = 1:
+        let connect = Ray { pos, dir: d };
+        let pdf = (1.0 - self.environment_prob) * light.prob * dist2 / (area * cos_light);
+        let mat = self.materials[instance.material_idx as usize];
+        let le = self.material_sample(&mat, uv).emissive.xyz();
+        Some((connect, wi, pdf, le))
+    }
+
+    // The normal n perturbed by the material's normal map, see normalMap in shade.slang.
+    fn normal_map(&self, mat: &Material, uv: Vec2, n: Vec3, tangent: Vec4) -> Vec3 {
+        let t = tangent.xyz() - n * n.dot(tangent.xyz());
+        if mat.normal_texture == 0 || t.length_squared() <= 0.0 {
+            return n;
+        }
+        let tn = self.textures.sample(mat.normal_texture, uv).xyz() * 2.0 - 1.0;
+        let t = t.normalize();
+        let b = n.cross(t) * tangent.w;
+        (tn.x * t + tn.y * b + tn.z * n)
+            .try_normalize()
+            .unwrap_or(n)
+    }
+
+    // The material at uv with its textures applied, see materialSample in shade.slang.
+    fn material_sample(&self, mat: &Material, uv: Vec2) -> Material {
+        let colour = self.textures.sample(mat.colour_texture, uv);
+        let emissive = self.textures.sample(mat.emissive_texture, uv);
+        let metallic_roughness = self.textures.sample(mat.metallic_roughness_texture, uv);
+        Material {
+            colour: mat.colour * srgb_to_linear(colour.xyz()).extend(colour.w),
+            emissive: (mat.emissive.xyz() * srgb_to_linear(emissive.xyz())).extend(mat.emissive.w),
+            metallic: mat.metallic * metallic_roughness.z,
+            roughness: mat.roughness * metallic_roughness.y,
+            ..*mat
+        }
+    }
+
+    // Solid angle pdf of sample_light picking the point d away on a light with normal n. The
+    // triangle's area cancels out of its chance of being picked.
+    fn light_pdf(&self, le: Vec3, d: Vec3, n: Vec3) -> f32 {
+        let total = self.lights[0].total;
+        let cos_light = n.dot(d.normalize()).abs();
+        if total <= 0.0 || cos_light <= 0.0 {
+            return 0.0;
+        }
+        (1.0 - self.environment_prob) * le.element_sum() / 3.0 * d.length_squared()
+            / (total * cos_light)
+    }
+
+    // Closest hit along the ray, skipping the triangle it last hit, see tlasFirstHit.
+    pub fn first_hit(&self, ray: &Ray, last_inst: u32, last_prim: u32) -> Option<Hit> {
+        self.trace(ray, last_inst, last_prim, f32::MAX, false)
+    }
+
+    // Hits closer than t, the closest one or with any_hit whichever is found first.
+    fn trace(
+        &self,
+        ray: &Ray,
+        last_inst: u32,
+        last_prim: u32,
+        mut t: f32,
+        any_hit: bool,
+    ) -> Option<Hit> {
+        let mut hit = None;
+
+        traverse(&self.tlas_nodes, |node| {
+            let (hit_aabb, tmin, tmax) = ray_box_intersect(ray, &node.aabb, t);
+            let hit_aabb = hit_aabb && (tmax >= 0.0 || tmin >= 0.0);
+            if !hit_aabb || node.is_leaf == 0 {
+                return ControlFlow::Continue(hit_aabb);
+            }
+
+            for i in node.start..node.end {
+                let instance_id = self.tlas_to_instances[i as usize];
+                let instance = self.instances[instance_id as usize];
+                let (m, mi) = self.matrices[instance.transform_idx as usize];
+
+                let r = Ray {
+                    pos: mi.transform_point3(ray.pos),
+                    dir: mi.transform_vector3(ray.dir),
+                };
+
+                if let Some(mut h) =
+                    self.blas_first_hit(&r, instance_id, last_inst, last_prim, any_hit, t)
+                {
+                    h.position = m.transform_point3(h.position);
+                    h.normal = m.transform_vector3(h.normal).normalize();
+                    h.tangent = m.transform_vector3(h.tangent.xyz()).extend(h.tangent.w);
+                    h.front_face = h.normal.dot(ray.dir) < 0.0;
+                    h.instance_id = instance_id;
+                    t = h.t;
+                    hit = Some(h);
+                    if any_hit {
+                        return ControlFlow::Break(());
+                    }
+                }
+            }
+            ControlFlow::Continue(true)
+        });
+
+        hit
+    }
+
+    // Closest hit within one instance's geometry, in its object space, see blasFirstHit.
+    fn blas_first_hit(
+        &self,
+        ray: &Ray,
+        instance_id: u32,
+        last_inst: u32,
+        last_prim: u32,
+        any_hit: bool,
+        mut t: f32,
+    ) -> Option<Hit> {
+        let instance = self.instances[instance_id as usize];
+        let geometry = self.geometries[instance.geometry_idx as usize];
+        let mesh = &geometry.mesh;
+        let mut hit = None;
+
+        traverse(&geometry.nodes, |node| {
+            let (hit_aabb, _, _) = ray_box_intersect(ray, &node.aabb, t);
+            if !hit_aabb || node.is_leaf == 0 {
+                return ControlFlow::Continue(hit_aabb);
+            }
+
+            for p in node.start..node.end {
+                if p == last_prim && instance_id == last_inst {
+                    continue;
+                }
+                let face = mesh.faces[p as usize];
+                let tri = [face.x, face.y, face.z].map(|v| {
+                    (
+                        mesh.positions[v as usize].xyz(),
+                        mesh.normals[v as usize].xyz(),
+                        mesh.uvs.get(v as usize).copied().unwrap_or_default(),
+                        mesh.tangents.get(v as usize).copied().unwrap_or_default(),
+                    )
+                });
+                if let Some(mut h) = ray_tri_intersect(ray, tri, t) {
+                    h.triangle_id = p;
+                    t = h.t;
+                    hit = Some(h);
+                    if any_hit {
+                        return ControlFlow::Break(());
+                    }
+                }
+            }
+            ControlFlow::Continue(true)
+        });
+
+        hit
+    }
+}
+
+// The stackless walk: a node that is hit moves on to its left child, a miss or a finished leaf
+// follows the skip pointer (stored in right), and skipping back to the root ends it. Visiting
+// a node can also end it early.
+fn traverse(nodes: &[BVHNodeGPU], mut visit: impl FnMut(&BVHNodeGPU) -> ControlFlow<(), bool>) {
+    let mut current = 0;
+    loop {
+        let node = &nodes[current];
+        let ControlFlow::Continue(hit) = visit(node) else {
+            return;
+        };
+        current = if hit && node.is_leaf == 0 {
+            node.left
+        } else {
+            node.right
+        } as usize;
+
+        if current == 0 {
+            return;
+        }
+    }
+}
+
+fn ray_box_intersect(ray: &Ray, aabb: &AABBGPU, t: f32) -> (bool, f32, f32) {
+    let lb = Vec3::from(aabb.lower_bound);
+    let ub = Vec3::from(aabb.upper_bound);
+    let dir_inv = 1.0 / ray.dir;
+
+    let mut tmin = f32::MIN;
+    let mut tmax = t;
+    for d in 0..3 {
+        let (bmin, bmax) = if dir_inv[d] >= 0.0 {
+            (lb[d], ub[d])
+        } else {
+            (ub[d], lb[d])
+        };
+        tmin = tmin.max((bmin - ray.pos[d]) * dir_inv[d]);
+        tmax = tmax.min((bmax - ray.pos[d]) * dir_inv[d]);
+    }
+
+    (tmin <= tmax, tmin, tmax)
+}
+
+// Möller–Trumbore, with the normal, uv and tangent interpolated from the vertices.
+fn ray_tri_intersect(ray: &Ray, tri: [(Vec3, Vec3, Vec2, Vec4); 3], t: f32) -> Option<Hit> {
+    let [(p0, n0, t0, s0), (p1, n1, t1, s1), (p2, n2, t2, s2)] = tri;
+
+    let e1 = p1 - p0;
+    let e2 = p2 - p0;
+    let q = ray.dir.cross(e2);
+    let alpha = e1.dot(q);
+    if alpha > -10e-8 && alpha < 10e-8 {
+        return None;
+    }
+    let f = 1.0 / alpha;
+    let s = ray.pos - p0;
+    let u = f * s.dot(q);
+    if u < 0.0 {
+        return None;
+    }
+    let r = s.cross(e1);
+    let v = f * ray.dir.dot(r);
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let hit_t = f * e2.dot(r);
+    if hit_t > t || hit_t < 0.0 {
+        return None;
+    }
+
+    Some(Hit {
+        position: p0 + e1 * u + e2 * v,
+        normal: n0 * (1.0 - u - v) + n1 * u + n2 * v,
+        uv: t0 * (1.0 - u - v) + t1 * u + t2 * v,
+        tangent: (s0.xyz() * (1.0 - u - v) + s1.xyz() * u + s2.xyz() * v).extend(s0.w),
+        t: hit_t,
+        triangle_id: NO_HIT,
+        instance_id: NO_HIT,
+        front_face: true,
+    })
+}
+
+// A jittered ray through the pixel, see cameraRay in sample.slang.
+fn camera_ray(
+    camera: &CameraData,
+    dims: (u32, u32),
+    pixel: (u32, u32),
+    rng: &mut StdRng,
+) -> Option<Ray> {
+    let forward = Vec3::from(camera.forward);
+    let up = Vec3::from(camera.up);
+    let right = forward.cross(up);
+    let size = Vec2::from(camera.dims);
+
+    let d = Vec2::new(rng.random(), rng.random());
+    let uv = Vec2::new(
+        (pixel.0 as f32 + d.x) / dims.0 as f32,
+        (pixel.1 as f32 + d.y) / dims.1 as f32,
+    );
+    let p = Vec2::new(2.0 * uv.x - 1.0, 1.0 - 2.0 * uv.y);
+
+    let mut pos = Vec3::from(camera.position);
+    let mut dir = match camera.projection() {
+        Projection::Perspective => {
+            forward * camera.focal_length + right * size.x * p.x + up * size.y * p.y
+        }
+        Projection::Orthographic => {
+            let half_height = 0.5 * camera.ortho_height;
+            let half_width = half_height * size.x / size.y;
+            pos += right * half_width * p.x + up * half_height * p.y;
+            forward
+        }
+        Projection::Fisheye => {
+            let q = p * size / size.min_element();
+            let r = q.length();
+            if r > 1.0 {
+                return None;
+            }
+            let theta = r * 0.5 * camera.fisheye_fov;
+            let side = if r > 0.0 {
+                (right * q.x + up * q.y) / r
+            } else {
+                Vec3::ZERO
+            };
+            let dir = forward * theta.cos() + side * theta.sin();
+            return Some(Ray { pos, dir });
+        }
+        Projection::Equirectangular => {
+            let lon = p.x * PI;
+            let lat = p.y * 0.5 * PI;
+            let dir = lat.cos() * (forward * lon.cos() + right * lon.sin()) + up * lat.sin();
+            return Some(Ray { pos, dir });
+        }
+    };
+
+    if camera.aperture > 0.0 {
+        let focus = pos + dir * (camera.focus_distance / dir.dot(forward));
+        let lens = aperture_sample(camera, rng) * camera.aperture;
+        pos += right * lens.x + up * lens.y;
+        dir = focus - pos;
+    }
+
+    Some(Ray {
+        pos,
+        dir: dir.normalize(),
+    })
+}
+
+// A point on the lens, see apertureSample in sample.slang.
+fn aperture_sample(camera: &CameraData, rng: &mut StdRng) -> Vec2 {
+    let u: f32 = rng.random();
+    let v: f32 = rng.random();
+    if camera.blades < 3 {
+        let r = u.sqrt();
+        let phi = 2.0 * PI * v;
+        return r * Vec2::new(phi.cos(), phi.sin());
+    }
+
+    let blade = ((rng.random::<f32>() * camera.blades as f32) as u32).min(camera.blades - 1);
+    let step = 2.0 * PI / camera.blades as f32;
+    let a = camera.blade_rotation + blade as f32 * step;
+    let c0 = Vec2::new(a.cos(), a.sin());
+    let c1 = Vec2::new((a + step).cos(), (a + step).sin());
+
+    let (u, v) = if u + v > 1.0 {
+        (1.0 - u, 1.0 - v)
+    } else {
+        (u, v)
+    };
+    u * c0 + v * c1
+}
+
+// Bends a normal facing away from wo back to just above the horizon, see towardsView.
+fn towards_view(n: Vec3, wo: Vec3) -> Vec3 {
+    let c = n.dot(wo);
+    if c >= 1e-3 {
+        n
+    } else {
+        (n + (1e-3 - c) * wo).normalize()
+    }
+}
+
+// Colour and emissive textures are stored in srgb.
+fn srgb_to_linear(c: Vec3) -> Vec3 {
+    Vec3::from(c.to_array().map(|c| {
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    }))
+}
+
+fn mix(a: Vec3, b: Vec3, t: f32) -> Vec3 {
+    (1.0 - t) * a + t * b
+}
+
+fn heaviside(x: f32) -> f32 {
+    if x > 0.0 { 1.0 } else { 0.0 }
+}
+
+fn schlick(f0: Vec3, cos: f32) -> Vec3 {
+    f0 + (1.0 - f0) * (1.0 - cos.abs()).powi(5)
+}
+
+fn dielectric_f0(ior: f32) -> f32 {
+    ((1.0 - ior) / (1.0 + ior)).powi(2)
+}
+
+// Roughness is clamped a little so the specular lobes stay finite to evaluate and sample.
+fn alpha(ms: &Material) -> f32 {
+    ms.roughness.powi(2).max(1e-3)
+}
+
+// Transmission is thin walled, so the transmitted lobe is the reflected one mirrored through the
+// surface.
+fn mirror(w: Vec3, n: Vec3) -> Vec3 {
+    w - 2.0 * n.dot(w) * n
+}
+
+fn ggx_d(nh: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    heaviside(nh) * a2 / (PI * (nh * nh * (a2 - 1.0) + 1.0).powi(2))
+}
+
+// Based on https://registry.khronos.org/glTF/specs/2.0/glTF-2.0.html#specular-brdf
+fn specular_brdf(wi: Vec3, wo: Vec3, n: Vec3, alpha: f32) -> f32 {
+    let h = (wo + wi).normalize();
+    let a2 = alpha * alpha;
+
+    let nwi2 = n.dot(wi).powi(2);
+    let nwo2 = n.dot(wo).powi(2);
+    let v = heaviside(h.dot(wi)) / (n.dot(wi).abs() + (a2 + (1.0 - a2) * nwi2).sqrt())
+        * heaviside(h.dot(wo))
+        / (n.dot(wo).abs() + (a2 + (1.0 - a2) * nwo2).sqrt());
+
+    v * ggx_d(n.dot(h), alpha)
+}
+
+fn diffuse_brdf(colour: Vec3) -> Vec3 {
+    colour / PI
+}
+
+// Based on https://registry.khronos.org/glTF/specs/2.0/glTF-2.0.html#metal-brdf-and-dielectric-brdf
+// Both wi and wo point away from the surface, and n faces wo.
+fn material(wi: Vec3, wo: Vec3, n: Vec3, ms: &Material) -> Vec3 {
+    let alpha = alpha(ms);
+    let colour = ms.colour.xyz();
+    let f0 = Vec3::splat(dielectric_f0(ms.ior));
+
+    if n.dot(wi) > 0.0 {
+        let h = (wo + wi).normalize();
+        let spec = specular_brdf(wi, wo, n, alpha);
+        let fr = schlick(f0, wo.dot(h)).x;
+        let dielectric =
+            (1.0 - fr) * (1.0 - ms.transmission) * diffuse_brdf(colour) + Vec3::splat(fr * spec);
+        let metallic = schlick(colour, wo.dot(h)) * spec;
+        mix(dielectric, metallic, ms.metallic)
+    } else {
+        let wr = mirror(wi, n);
+        let h = (wo + wr).normalize();
+        let fr = schlick(f0, wo.dot(h)).x;
+        (1.0 - ms.metallic)
+            * (1.0 - fr)
+            * ms.transmission
+            * specular_brdf(wr, wo, n, alpha)
+            * colour
+    }
+}
+
+// Chances of sampling the diffuse, specular and transmission lobes, by roughly how much
+// each of them carries.
+fn lobe_probs(wo: Vec3, n: Vec3, ms: &Material) -> Vec3 {
+    let fr = schlick(Vec3::splat(dielectric_f0(ms.ior)), n.dot(wo)).x;
+    let dielectric = (1.0 - ms.metallic) * (1.0 - fr);
+    Vec3::new(
+        dielectric * (1.0 - ms.transmission),
+        1.0 - dielectric,
+        dielectric * ms.transmission,
+    )
+}
+
+fn smith_g1(nw: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    2.0 * nw / (nw + (a2 + (1.0 - a2) * nw * nw).sqrt())
+}
+
+// Half vector from the normals visible from wo, see Heitz, "Sampling the GGX Distribution of
+// Visible Normals" (2018).
+fn ggx_vndf_sample(wo: Vec3, n: Vec3, alpha: f32, rng: &mut StdRng) -> Vec3 {
+    let v = to_local(wo, n);
+    let vh = Vec3::new(alpha * v.x, alpha * v.y, v.z).normalize();
+    let len2 = vh.x * vh.x + vh.y * vh.y;
+    let t1 = if len2 > 0.0 {
+        Vec3::new(-vh.y, vh.x, 0.0) / len2.sqrt()
+    } else {
+        Vec3::X
+    };
+    let t2 = vh.cross(t1);
+
+    let r = rng.random::<f32>().sqrt();
+    let phi = 2.0 * PI * rng.random::<f32>();
+    let p1 = r * phi.cos();
+    let s = 0.5 * (1.0 + vh.z);
+    let p2 = (1.0 - s) * (1.0 - p1 * p1).sqrt() + s * r * phi.sin();
+    let nh = p1 * t1 + p2 * t2 + (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt() * vh;
+
+    to_world(
+        Vec3::new(alpha * nh.x, alpha * nh.y, nh.z.max(0.0)).normalize(),
+        n,
+    )
+}
+
+// Solid angle pdf of reflecting wo into wi about a half vector from ggx_vndf_sample.
+fn ggx_pdf(wi: Vec3, wo: Vec3, n: Vec3, alpha: f32) -> f32 {
+    let nwo = n.dot(wo);
+    let h = (wo + wi).normalize_or_zero();
+    if nwo <= 0.0 || wo.dot(h) <= 0.0 {
+        return 0.0;
+    }
+    smith_g1(nwo, alpha) * ggx_d(n.dot(h), alpha) / (4.0 * nwo)
+}
+
+// Picks a lobe and then a direction from it.
+fn bsdf_sample(wo: Vec3, n: Vec3, ms: &Material, rng: &mut StdRng) -> Vec3 {
+    let probs = lobe_probs(wo, n, ms);
+    let u: f32 = rng.random();
+    if u < probs.x {
+        return cosine_hemisphere_sample(n, rng);
+    }
+
+    let h = ggx_vndf_sample(wo, n, alpha(ms), rng);
+    let wi = 2.0 * wo.dot(h) * h - wo;
+    if u < probs.x + probs.y {
+        wi
+    } else {
+        mirror(wi, n)
+    }
+}
+
+// Chance of bsdf_sample giving wi through any of the lobes, as the specular lobe can dip
+// below the surface and transmission can come back above it.
+fn bsdf_pdf(wi: Vec3, wo: Vec3, n: Vec3, ms: &Material) -> f32 {
+    let probs = lobe_probs(wo, n, ms);
+    let alpha = alpha(ms);
+    probs.x * n.dot(wi).max(0.0) / PI
+        + probs.y * ggx_pdf(wi, wo, n, alpha)
+        + probs.z * ggx_pdf(mirror(wi, n), wo, n, alpha)
+}
+
+fn power_heuristic(pdf: f32, other: f32) -> f32 {
+    let sum = pdf * pdf + other * other;
+    if sum > 0.0 { pdf * pdf / sum } else { 0.0 }
+}
+
+fn unit_disk_sample(rng: &mut StdRng) -> Vec2 {
+    loop {
+        let p = Vec2::new(
+            2.0 * rng.random::<f32>() - 1.0,
+            2.0 * rng.random::<f32>() - 1.0,
+        );
+        if p.length() <= 1.0 {
+            return p;
+        }
+    }
+}
+
+// Tangents completing a frame where n is up.
+fn basis(n: Vec3) -> (Vec3, Vec3) {
+    let temp = if n.x.abs() > 0.9 { Vec3::Y } else { Vec3::X };
+    let t1 = n.cross(temp).normalize();
+    (t1, n.cross(t1))
+}
+
+fn to_world(v: Vec3, n: Vec3) -> Vec3 {
+    let (t1, t2) = basis(n);
+    v.x * t1 + v.y * t2 + v.z * n.normalize()
+}
+
+fn to_local(v: Vec3, n: Vec3) -> Vec3 {
+    let (t1, t2) = basis(n);
+    Vec3::new(v.dot(t1), v.dot(t2), v.dot(n.normalize()))
+}
+
+fn cosine_hemisphere_sample(n: Vec3, rng: &mut StdRng) -> Vec3 {
+    let d = unit_disk_sample(rng);
+    let z = (1.0 - d.x * d.x - d.y * d.y).max(0.0).sqrt();
+    to_world(d.extend(z), n)
+}
+
+// Tonemaps radiance from CpuScene::render into the same image a gpu render would save.
+pub fn to_image(
+    radiance: &[Vec3],
+    dims: (u32, u32),
+    display: &DisplaySettings,
+) -> image::RgbaImage {
+    let exposure = display.exposure_for(radiance).exp2();
+    // The gpu output is mirrored horizontally when read back, match it:
+    image::RgbaImage::from_fn(dims.0, dims.1, |x, y| {
+        let rad = radiance[((dims.0 - 1 - x) + y * dims.0) as usize];
+        let c = display
+            .tonemapper
+            .apply(rad * exposure, display.white_point);
+        image::Rgba([
+            (c.x * 255.0) as u8,
+            (c.y * 255.0) as u8,
+            (c.z * 255.0) as u8,
+            255,
+        ])
+    })
+}
+
+// The untonemapped radiance, mirrored the same as to_image.
+pub fn to_hdr_image(radiance: &[Vec3], dims: (u32, u32)) -> image::Rgb32FImage {
+    image::Rgb32FImage::from_fn(dims.0, dims.1, |x, y| {
+        image::Rgb(radiance[((dims.0 - 1 - x) + y * dims.0) as usize].to_array())
+    })
+}
+
+// Loads the scene without touching the gpu, renders it on the cpu and writes it to disk.
+pub struct CpuApp {
+    bevy_app: BevyApp,
+    output: PathBuf,
+}
+
+impl CpuApp {
+    pub fn new(bevy_app: BevyApp, output: PathBuf) -> Self {
+        Self { bevy_app, output }
+    }
+
+    pub fn run(mut self) -> anyhow::Result<()> {
+        // Meshes and textures are loaded in the background, wait for all of them:
+        self.bevy_app.run();
+        while self.bevy_app.world.resource::<MeshServer>().is_loading()
+            || self.bevy_app.world.resource::<TextureServer>().is_loading()
+        {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            self.bevy_app.run();
+        }
+
+        let args = self.bevy_app.world.resource::<Args>().clone();
+        let pathtracer = Pathtracer::primary(&args);
+
+        let mut camera = CameraData::new();
+        let scene_camera = self
+            .bevy_app
+            .world
+            .get_resource::<SceneFile>()
+            .and_then(|s| s.camera.clone());
+        if let Some(c) = &scene_camera {
+            camera.set_view(c.position, c.forward, c.up, c.focal_length);
+        }
+        camera.set_aspect(args.width() as f32 / args.height() as f32);
+        camera.set_lens(&args.lens(scene_camera.as_ref().map(|c| c.lens).unwrap_or_default()));
+        camera.set_projection(
+            &args.projection(scene_camera.map(|c| c.projection).unwrap_or_default()),
+        );
+
+        let Some(pool) = self.bevy_app.world.remove_resource::<ThreadPool>() else {
+            anyhow::bail!("Expected a threadpool");
+        };
+        let Some(scene) = CpuScene::from_world(&mut self.bevy_app.world) else {
+            anyhow::bail!("Nothing in the scene to render");
+        };
+
+        // Focus on what's straight ahead, see focus.slang:
+        if args.autofocus {
+            let pos = Vec3::from(camera.position);
+            let ray = Ray {
+                pos,
+                dir: Vec3::from(camera.forward),
+            };
+            match scene.first_hit(&ray, NO_HIT, NO_HIT) {
+                Some(hit) => {
+                    camera.focus_distance = hit.position.distance(pos);
+                    tracing::info!("Autofocused at {:.3}", camera.focus_distance);
+                }
+                None => tracing::warn!("Nothing in the centre of the image to focus on"),
+            }
+        }
+
+        let start = Instant::now();
+        tracing::info!(
+            "Rendering {}x{} at {} samples per pixel on the cpu",
+            pathtracer.dims.0,
+            pathtracer.dims.1,
+            pathtracer.samples_per_pixel
+        );
+        let radiance = pool.0.install(|| scene.render(&camera, &pathtracer));
+        tracing::info!("Rendered in {:.1}s", start.elapsed().as_secs_f64());
+
+        let merge_into = args.merge_into.as_deref();
+        if is_hdr_output(&self.output) {
+            save_render(
+                to_hdr_image(&radiance, pathtracer.dims),
+                &self.output,
+                pathtracer.crop,
+                args.cropped_output,
+                merge_into,
+                |i| i.into_rgb32f(),
+            )?;
+        } else {
+            let display = DisplaySettings::from_args(&args);
+            save_render(
+                to_image(&radiance, pathtracer.dims, &display),
+                &self.output,
+                pathtracer.crop,
+                args.cropped_output,
+                merge_into,
+                |i| i.into_rgba8(),
+            )?;
+        }
+        tracing::info!("Wrote {}", self.output.display());
+
+        Ok(())
+    }
+}
+
+// Running mean and variance of a pixel's luminance, for adaptive sampling.
+#[derive(Default)]
+struct Welford {
+    n: u32,
+    mean: f32,
+    m2: f32,
+}
+
+impl Welford {
+    fn add(&mut self, x: f32) {
+        self.n += 1;
+        let delta = x - self.mean;
+        self.mean += delta / self.n as f32;
+        self.m2 += delta * (x - self.mean);
+    }
+
+    // Same as relativeError in sample.slang.
+    fn relative_error(&self) -> f32 {
+        let n = self.n as f32;
+        let variance = self.m2 / (n - 1.0);
+        (variance / n).sqrt() / (self.mean + 1e-4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blas::BLAS,
+        bvh::{BVH, BuildMethod, SAHSettings},
+        display::Tonemapper,
+        mesh::Mesh,
+    };
+
+    fn mesh_data(mesh: Mesh, method: &BuildMethod) -> MeshData {
+        let blas = BLAS::new(mesh, method);
+        MeshData {
+            aabb: blas.node_bounds(0),
+            nodes: blas
+                .nodes
+                .iter()
+                .copied()
+                .map(BVHNodeGPU::from)
+                .collect_vec(),
+            mesh: blas.mesh,
+        }
+    }
+
+    // Overlapping random triangles, so the BLAS has plenty of nodes to get wrong.
+    fn triangle_soup(count: usize, rng: &mut StdRng) -> Mesh {
+        let positions = (0..count * 3)
+            .map(|_| {
+                Vec3::new(rng.random(), rng.random(), rng.random()).extend(1.0) * 2.0
+                    - Vec4::new(1.0, 1.0, 1.0, 1.0)
+            })
+            .collect_vec();
+        let indices = (0..count as u32 * 3).collect_vec();
+        Mesh::new(positions, indices, vec![], vec![], vec![])
+    }
+
+    // Every triangle of every instance moved into world space and intersected one by one.
+    fn brute_force_hit(scene: &CpuScene, ray: &Ray) -> Option<f32> {
+        let mut closest: Option<f32> = None;
+        for instance in scene.instances.iter() {
+            let mesh = &scene.geometries[instance.geometry_idx as usize].mesh;
+            let (m, _) = scene.matrices[instance.transform_idx as usize];
+            for face in mesh.faces.iter() {
+                let [p0, p1, p2] = [face.x, face.y, face.z]
+                    .map(|v| m.transform_point3(mesh.positions[v as usize].xyz()));
+
+                // Moller-Trumbore, written out again rather than trusting ray_tri_intersect:
+                let e1 = p1 - p0;
+                let e2 = p2 - p0;
+                let q = ray.dir.cross(e2);
+                let det = e1.dot(q);
+                if det.abs() < 1e-9 {
+                    continue;
+                }
+                let s = ray.pos - p0;
+                let u = s.dot(q) / det;
+                let r = s.cross(e1);
+                let v = ray.dir.dot(r) / det;
+                let t = e2.dot(r) / det;
+                if u < 0.0 || v < 0.0 || u + v > 1.0 || t < 0.0 {
+                    continue;
+                }
+                if closest.is_none_or(|c| t < c) {
+                    closest = Some(t);
+                }
+            }
+        }
+        closest
+    }
+
+    #[test]
+    fn trace_matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let environment = Environment::new(1, 1, vec![Vec3::ONE], 0.0, 1.0);
+        let textures = TextureServer::default();
+
+        for method in [
+            BuildMethod::SAH(SAHSettings::default()),
+            BuildMethod::Midpoint { threshold: 4 },
+        ] {
+            let geometries = [
+                mesh_data(Mesh::cube(), &method),
+                mesh_data(Mesh::rect(), &method),
+                mesh_data(triangle_soup(300, &mut rng), &method),
+            ];
+            let transform = |geometry: u32, scale: Vec3, rotation: Vec3, translation: Vec3| {
+                (
+                    geometry,
+                    Transform {
+                        scale: scale.extend(0.0),
+                        rotation: rotation.extend(0.0),
+                        translation: translation.extend(1.0),
+                    },
+                )
+            };
+            let placed = [
+                transform(
+                    0,
+                    Vec3::new(1.0, 2.0, 1.0),
+                    Vec3::new(0.3, 0.5, 0.0),
+                    Vec3::new(0.0, 0.0, 5.0),
+                ),
+                transform(0, Vec3::splat(0.5), Vec3::ZERO, Vec3::new(-2.0, -1.0, 4.0)),
+                transform(
+                    1,
+                    Vec3::splat(10.0),
+                    Vec3::new(-0.5 * PI, 0.0, 0.0),
+                    Vec3::new(0.0, -2.0, 5.0),
+                ),
+                transform(
+                    2,
+                    Vec3::splat(1.5),
+                    Vec3::new(0.1, -0.7, 0.2),
+                    Vec3::new(2.0, 1.0, 6.0),
+                ),
+                transform(
+                    2,
+                    Vec3::new(0.5, 3.0, 1.0),
+                    Vec3::new(1.0, 0.0, 0.4),
+                    Vec3::new(-2.0, 2.0, 8.0),
+                ),
+            ];
+
+            let transforms = placed.iter().map(|(_, t)| *t).collect_vec();
+            let instances = placed
+                .iter()
+                .enumerate()
+                .map(|(i, (geometry, _))| Instance {
+                    transform_idx: i as u32,
+                    geometry_idx: *geometry,
+                    material_idx: 0,
+                })
+                .collect_vec();
+            let aabbs = geometries.iter().map(|g| g.aabb).collect_vec();
+            let tlas = TLAS::new(&aabbs, &transforms, &instances, &method);
+
+            let scene = CpuScene {
+                geometries: geometries.iter().collect_vec(),
+                instances,
+                matrices: transforms
+                    .iter()
+                    .map(|t| (t.matrix(), t.matrix().inverse()))
+                    .collect_vec(),
+                materials: vec![Material::default()],
+                tlas_nodes: tlas.nodes.into_iter().map(BVHNodeGPU::from).collect_vec(),
+                tlas_to_instances: tlas.instance_ids.iter().map(|i| *i as u32).collect_vec(),
+                lights: vec![],
+                environment: &environment,
+                textures: &textures,
+                environment_prob: 1.0,
+            };
+
+            let mut hits = 0;
+            // From in front of everything towards somewhere among it:
+            let mut point = |lb: Vec3, ub: Vec3| {
+                lb + Vec3::new(rng.random(), rng.random(), rng.random()) * (ub - lb)
+            };
+            for i in 0..2000 {
+                let pos = point(Vec3::new(-3.0, -3.0, 0.0), Vec3::new(3.0, 3.0, 2.0));
+                let target = point(Vec3::new(-4.0, -3.0, 3.0), Vec3::new(4.0, 4.0, 9.0));
+                let ray = Ray {
+                    pos,
+                    dir: (target - pos).normalize(),
+                };
+
+                let expected = brute_force_hit(&scene, &ray);
+                let hit = scene.first_hit(&ray, NO_HIT, NO_HIT);
+                match (expected, hit) {
+                    (None, None) => {}
+                    (Some(t), Some(h)) => {
+                        hits += 1;
+                        assert!(
+                            (h.t - t).abs() <= 1e-3 * t.max(1.0),
+                            "ray {i} {ray:?} hit at {} rather than {t}",
+                            h.t
+                        );
+                        assert!(h.position.distance(ray.pos + ray.dir * t) <= 1e-3 * t.max(1.0));
+                    }
+                    (t, h) => panic!("ray {i} {ray:?} expected a hit at {t:?}, traced {h:?}"),
+                }
+            }
+            // Most rays should hit something, or this isn't testing much:
+            assert!(hits > 1000, "only {hits} rays hit");
+        }
+    }
+
+    // The gpu's output buffer is mirrored horizontally and flipped back when it's read, which
+    // camera_ray and to_image copy. Between them a saved image has to show what's to the
+    // camera's right (the way D moves it) on the right, and what's above it at the top.
+    #[test]
+    fn images_are_not_mirrored() {
+        let mut camera = CameraData::new();
+        camera.set_view(Vec3::ZERO, Vec3::Z, Vec3::Y, 1.0);
+        let dims = (8, 6);
+        camera.set_aspect(dims.0 as f32 / dims.1 as f32);
+        let right = Vec3::Y.cross(Vec3::Z);
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let radiance = (0..dims.0 * dims.1)
+            .map(|i| {
+                let ray = camera_ray(&camera, dims, (i % dims.0, i / dims.0), &mut rng).unwrap();
+                let lit = |b: bool| if b { 1.0 } else { 0.0 };
+                Vec3::new(lit(ray.dir.dot(right) > 0.0), lit(ray.dir.y > 0.0), 0.0)
+            })
+            .collect_vec();
+
+        let display = DisplaySettings {
+            tonemapper: Tonemapper::Linear,
+            exposure: 0.0,
+            auto_exposure: false,
+            white_point: 4.0,
+        };
+        let image = to_image(&radiance, dims, &display);
+        for (x, y, p) in image.enumerate_pixels() {
+            assert_eq!(p[0] > 0, x >= dims.0 / 2, "right half wrong at {x}, {y}");
+            assert_eq!(p[1] > 0, y < dims.1 / 2, "top half wrong at {x}, {y}");
+        }
+    }
+    // Mean and standard error of n samples of f.
+    fn estimate(n: u32, mut f: impl FnMut() -> f32) -> (f32, f32) {
+        let (mut sum, mut sum2) = (0.0f64, 0.0f64);
+        for _ in 0..n {
+            let x = f() as f64;
+            sum += x;
+            sum2 += x * x;
+        }
+        let mean = sum / n as f64;
+        let var = (sum2 / n as f64 - mean * mean).max(0.0);
+        (mean as f32, (var / n as f64).sqrt() as f32)
+    }
+
+    // White furnace check of the bsdf sampling. For a white material seen from a few angles, how
+    // much light gets reflected is estimated by importance sampling the bsdf and by sampling the
+    // sphere uniformly. The two have to agree if bsdf_pdf matches bsdf_sample. Reflecting more
+    // than comes in isn't checked, the glTF fresnel mix does that for smooth dielectrics at
+    // grazing angles whatever the sampling.
+    fn furnace(metallic: f32, roughness: f32, transmission: f32) {
+        const SAMPLES: u32 = 1 << 16;
+        let ms = Material {
+            colour: Vec4::ONE,
+            metallic,
+            roughness,
+            transmission,
+            ..Default::default()
+        };
+        let n = Vec3::Z;
+        let mut rng = StdRng::seed_from_u64(0);
+        for cos in [1.0f32, 0.7, 0.3, 0.1] {
+            let wo = Vec3::new((1.0 - cos * cos).sqrt(), 0.0, cos);
+            let reflected =
+                |wi: Vec3| material(wi, wo, n, &ms).element_sum() / 3.0 * n.dot(wi).abs();
+
+            let (s, s_err) = estimate(SAMPLES, || {
+                let wi = bsdf_sample(wo, n, &ms, &mut rng);
+                let pdf = bsdf_pdf(wi, wo, n, &ms);
+                if pdf > 0.0 { reflected(wi) / pdf } else { 0.0 }
+            });
+            let (u, u_err) = estimate(SAMPLES, || {
+                let z = 2.0 * rng.random::<f32>() - 1.0;
+                let phi = 2.0 * PI * rng.random::<f32>();
+                let r = (1.0 - z * z).sqrt();
+                reflected(Vec3::new(r * phi.cos(), r * phi.sin(), z)) * 4.0 * PI
+            });
+
+            // Four standard errors apart is well past chance:
+            let tolerance = 4.0 * (s_err * s_err + u_err * u_err).sqrt() + 1e-3;
+            assert!(
+                (s - u).abs() <= tolerance,
+                "roughness {roughness} at cos(wo) {cos}: sampled {s} ± {s_err}, uniform {u} ± {u_err}"
+            );
+        }
+    }
+
+    #[test]
+    fn furnace_metal() {
+        for roughness in [0.3, 0.6, 1.0] {
+            furnace(1.0, roughness, 0.0);
+        }
+    }
+
+    #[test]
+    fn furnace_plastic() {
+        for roughness in [0.3, 1.0] {
+            furnace(0.0, roughness, 0.0);
+        }
+    }
+
+    #[test]
+    fn furnace_glass() {
+        furnace(0.0, 0.5, 1.0);
+    }
+}
@@ -0,0 +1,499 @@
+use bevy_ecs::prelude::*;
+use clap::ValueEnum;
+use glam::{Mat3, Vec3};
+use serde::{Deserialize, Serialize};
+use wgpu::{include_spirv, util::DeviceExt};
+use winit::{event::WindowEvent, keyboard::KeyCode};
+
+use crate::{
+    app::BevyApp,
+    cli::Args,
+    pathtracer::{Pathtracer, PathtracerOutput, output_row_pitch, pathtracer_output_sync_system},
+    pathtracer_manager::pathtracer_phase_execute,
+    render::render_system,
+    render_resources::{RenderDevice, RenderQueue},
+    schedule,
+    winnit::WinitWindowEvent,
+};
+
+// Matches display.slang.
+const HISTOGRAM_BINS: usize = 64;
+const MIN_LOG_LUM: f32 = -12.0;
+const MAX_LOG_LUM: f32 = 8.0;
+const LOW_PERCENTILE: f32 = 0.1;
+const HIGH_PERCENTILE: f32 = 0.9;
+const KEY: f32 = 0.18;
+
+pub fn initialize(app: &mut BevyApp) {
+    let settings = DisplaySettings::from_args(app.world.resource::<Args>());
+    app.world.insert_resource(settings);
+    app.world.get_resource_or_init::<Schedules>().add_systems(
+        schedule::Update,
+        (
+            display_input_system,
+            display_phase_sync.after(pathtracer_output_sync_system),
+            display_phase_execute
+                .after(display_phase_sync)
+                .after(display_input_system)
+                .after(pathtracer_phase_execute)
+                .before(render_system),
+        ),
+    );
+}
+
+// In the order of DisplaySettings::tonemapper in display.slang.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Tonemapper {
+    /// Stephen Hill's fit of ACES
+    #[default]
+    Aces,
+    /// Extended Reinhard on luminance, reaching white at the white point
+    Reinhard,
+    /// Minimal AgX, desaturates bright colours towards white
+    Agx,
+    /// Khronos PBR Neutral, keeps colours true until the highlights
+    Neutral,
+    /// Clip to 0..1, no tonemapping
+    Linear,
+}
+
+// How the accumulated radiance is shown and saved. None of it affects the accumulation, so it
+// can change without starting it over.
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct DisplaySettings {
+    pub tonemapper: Tonemapper,
+    // In EV, on top of the automatic exposure if it's on:
+    pub exposure: f32,
+    pub auto_exposure: bool,
+    pub white_point: f32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Zeroable, bytemuck::Pod)]
+struct DisplayData {
+    dims: [u32; 2],
+    exposure: f32,
+    tonemapper: u32,
+    auto_exposure: u32,
+    white_point: f32,
+    output_stride: u32,
+    _pad: u32,
+}
+
+#[derive(Component)]
+pub struct DisplayPhase {
+    histogram_pipeline: wgpu::ComputePipeline,
+    exposure_pipeline: wgpu::ComputePipeline,
+    display_pipeline: wgpu::ComputePipeline,
+    settings_buffer: wgpu::Buffer,
+    // Also holds the luminance histogram and the automatic exposure, only touched on the gpu:
+    bind_group: wgpu::BindGroup,
+}
+
+// [ and ] step the exposure by half a stop, T cycles through the tonemappers and E toggles
+// automatic exposure.
+fn display_input_system(
+    mut we_reader: MessageReader<WinitWindowEvent>,
+    mut settings: ResMut<DisplaySettings>,
+) {
+    for WinitWindowEvent(e) in we_reader.read() {
+        let WindowEvent::KeyboardInput { event, .. } = e else {
+            continue;
+        };
+        let winit::keyboard::PhysicalKey::Code(key) = event.physical_key else {
+            continue;
+        };
+        if !event.state.is_pressed() || event.repeat {
+            continue;
+        }
+
+        match key {
+            KeyCode::BracketLeft => settings.exposure -= 0.5,
+            KeyCode::BracketRight => settings.exposure += 0.5,
+            KeyCode::KeyT => settings.tonemapper = settings.tonemapper.next(),
+            KeyCode::KeyE => settings.auto_exposure = !settings.auto_exposure,
+            _ => continue,
+        }
+        tracing::info!(
+            "Display: {:?} at {:+.1} EV{}",
+            settings.tonemapper,
+            settings.exposure,
+            if settings.auto_exposure {
+                " (auto)"
+            } else {
+                ""
+            }
+        );
+    }
+}
+
+fn display_phase_sync(
+    mut commands: Commands,
+    device: Res<RenderDevice>,
+    query: Query<(Entity, &PathtracerOutput), Changed<PathtracerOutput>>,
+) {
+    for (id, pto) in query.iter() {
+        commands
+            .entity(id)
+            .insert(DisplayPhase::new(&device.0, pto));
+    }
+}
+
+fn display_phase_execute(
+    device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+    settings: Res<DisplaySettings>,
+    query: Query<(&Pathtracer, &PathtracerOutput, &DisplayPhase)>,
+) {
+    for (pt, pto, dp) in query.iter() {
+        queue.0.write_buffer(
+            &dp.settings_buffer,
+            0,
+            bytemuck::bytes_of(&settings.data(pt.dims)),
+        );
+
+        let mut encoder = device
+            .0
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Display Encoder"),
+            });
+
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Display Pass"),
+            timestamp_writes: None,
+        });
+
+        compute_pass.set_bind_group(0, &dp.bind_group, &[]);
+        compute_pass.set_bind_group(1, &pto.source_bind_group, &[]);
+
+        if settings.auto_exposure {
+            compute_pass.set_pipeline(&dp.histogram_pipeline);
+            compute_pass.dispatch_workgroups(pt.dims.0.div_ceil(16), pt.dims.1.div_ceil(16), 1);
+
+            compute_pass.set_pipeline(&dp.exposure_pipeline);
+            compute_pass.dispatch_workgroups(1, 1, 1);
+        }
+
+        compute_pass.set_pipeline(&dp.display_pipeline);
+        compute_pass.dispatch_workgroups(pt.dims.0.div_ceil(8), pt.dims.1.div_ceil(8), 1);
+
+        drop(compute_pass);
+
+        queue.0.submit([encoder.finish()]);
+    }
+}
+
+impl Tonemapper {
+    fn next(self) -> Self {
+        let variants = Self::value_variants();
+        let i = variants.iter().position(|t| *t == self).unwrap();
+        variants[(i + 1) % variants.len()]
+    }
+
+    // Exposed radiance to display encoded colour, the same as toneMap in display.slang.
+    pub fn apply(self, hdr: Vec3, white_point: f32) -> Vec3 {
+        match self {
+            Tonemapper::Aces => linear_to_srgb(aces(hdr)),
+            Tonemapper::Reinhard => linear_to_srgb(reinhard(hdr, white_point)),
+            Tonemapper::Agx => agx(hdr),
+            Tonemapper::Neutral => linear_to_srgb(neutral(hdr)),
+            Tonemapper::Linear => linear_to_srgb(hdr),
+        }
+    }
+}
+
+impl DisplaySettings {
+    pub fn from_args(args: &Args) -> Self {
+        Self {
+            tonemapper: args.tonemapper(),
+            exposure: args.exposure(),
+            auto_exposure: args.auto_exposure,
+            white_point: args.white_point(),
+        }
+    }
+
+    fn data(&self, dims: (u32, u32)) -> DisplayData {
+        DisplayData {
+            dims: [dims.0, dims.1],
+            exposure: self.exposure,
+            tonemapper: self.tonemapper as u32,
+            auto_exposure: self.auto_exposure as u32,
+            white_point: self.white_point,
+            output_stride: output_row_pitch(dims.0) / 4,
+            _pad: 0,
+        }
+    }
+
+    // Total exposure in EV for an image, the cpu side of exposureMain.
+    pub fn exposure_for(&self, radiance: &[Vec3]) -> f32 {
+        if !self.auto_exposure {
+            return self.exposure;
+        }
+
+        let mut bins = [0u32; HISTOGRAM_BINS];
+        for rad in radiance {
+            bins[histogram_bin(luminance(*rad))] += 1;
+        }
+
+        let total: u32 = bins[1..].iter().sum();
+        if total == 0 {
+            return self.exposure;
+        }
+
+        let low = total as f32 * LOW_PERCENTILE;
+        let high = total as f32 * HIGH_PERCENTILE;
+        let (mut seen, mut sum, mut weight) = (0.0, 0.0, 0.0);
+        for (bin, count) in bins.iter().enumerate().skip(1) {
+            let count = *count as f32;
+            let inside = ((seen + count).min(high) - seen.max(low)).max(0.0);
+            sum += inside * bin_log_lum(bin);
+            weight += inside;
+            seen += count;
+        }
+
+        self.exposure + KEY.log2() - sum / weight.max(1.0)
+    }
+}
+
+impl DisplayPhase {
+    fn new(device: &wgpu::Device, pathtracer_output: &PathtracerOutput) -> Self {
+        let shader =
+            device.create_shader_module(include_spirv!(concat!(env!("OUT_DIR"), "/display.spv")));
+
+        let settings_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Display Settings Buffer"),
+            size: std::mem::size_of::<DisplayData>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        // Cleared by the exposure pass after it's read:
+        let histogram_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Luminance Histogram Buffer"),
+            contents: bytemuck::cast_slice(&[0u32; HISTOGRAM_BINS]),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+
+        // In EV:
+        let exposure_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Auto Exposure Buffer"),
+            contents: bytemuck::bytes_of(&0.0f32),
+            usage: wgpu::BufferUsages::STORAGE,
+        });
+
+        let storage_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: false },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Display Bind Group Layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                storage_entry(1),
+                storage_entry(2),
+            ],
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Display Bind Group"),
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: settings_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: histogram_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: exposure_buffer.as_entire_binding(),
+                },
+            ],
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Display Pipeline Layout"),
+            bind_group_layouts: &[
+                &bind_group_layout,
+                &pathtracer_output.source_bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = |label, entry_point| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(label),
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point: Some(entry_point),
+                compilation_options: wgpu::PipelineCompilationOptions {
+                    constants: &[],
+                    zero_initialize_workgroup_memory: false,
+                },
+                cache: None,
+            })
+        };
+
+        Self {
+            histogram_pipeline: pipeline("Display Histogram Pipeline", "histogramMain"),
+            exposure_pipeline: pipeline("Display Exposure Pipeline", "exposureMain"),
+            display_pipeline: pipeline("Display Pipeline", "displayMain"),
+            settings_buffer,
+            bind_group,
+        }
+    }
+}
+
+fn luminance(c: Vec3) -> f32 {
+    c.dot(Vec3::new(0.2126, 0.7152, 0.0722))
+}
+
+fn histogram_bin(lum: f32) -> usize {
+    if lum < MIN_LOG_LUM.exp2() {
+        return 0;
+    }
+    let t = ((lum.log2() - MIN_LOG_LUM) / (MAX_LOG_LUM - MIN_LOG_LUM)).clamp(0.0, 1.0);
+    ((t * (HISTOGRAM_BINS - 1) as f32) as usize).min(HISTOGRAM_BINS - 2) + 1
+}
+
+fn bin_log_lum(bin: usize) -> f32 {
+    MIN_LOG_LUM + (bin as f32 - 0.5) / (HISTOGRAM_BINS - 1) as f32 * (MAX_LOG_LUM - MIN_LOG_LUM)
+}
+
+fn linear_to_srgb(c: Vec3) -> Vec3 {
+    c.clamp(Vec3::ZERO, Vec3::ONE).map(|c| {
+        if c <= 0.0031308 {
+            c * 12.92
+        } else {
+            1.055 * c.powf(1.0 / 2.4) - 0.055
+        }
+    })
+}
+
+fn aces(hdr: Vec3) -> Vec3 {
+    let m1 = Mat3::from_cols_array(&[
+        0.59719, 0.35458, 0.04823, //
+        0.07600, 0.90834, 0.01566, //
+        0.02840, 0.13383, 0.83770,
+    ])
+    .transpose();
+    let m2 = Mat3::from_cols_array(&[
+        1.60475, -0.53108, -0.07367, //
+        -0.10208, 1.10813, -0.00605, //
+        -0.00327, -0.07276, 1.07602,
+    ])
+    .transpose();
+
+    let v = m1 * hdr;
+    let a = v * (v + 0.0245786) - 0.000090537;
+    let b = v * (0.983729 * v + 0.432951) + 0.238081;
+    (m2 * (a / b)).clamp(Vec3::ZERO, Vec3::ONE)
+}
+
+fn reinhard(hdr: Vec3, white_point: f32) -> Vec3 {
+    let l = luminance(hdr);
+    if l <= 0.0 {
+        return Vec3::ZERO;
+    }
+    let ld = l * (1.0 + l / (white_point * white_point)) / (1.0 + l);
+    hdr * (ld / l)
+}
+
+fn agx(hdr: Vec3) -> Vec3 {
+    let inset = Mat3::from_cols_array(&[
+        0.84247906, 0.04232824, 0.04237565, //
+        0.0784336, 0.87846863, 0.0784336, //
+        0.07922375, 0.07916613, 0.879143,
+    ]);
+    let outset = Mat3::from_cols_array(&[
+        1.196879,
+        -0.05289685,
+        -0.05297164, //
+        -0.09802088,
+        1.1519031,
+        -0.09804345, //
+        -0.09902974,
+        -0.09896118,
+        1.1510737,
+    ]);
+    let (min_ev, max_ev) = (-12.47393f32, 4.026069f32);
+
+    let v = (inset * hdr).max(Vec3::splat(1e-10)).map(f32::log2);
+    let v = (v.clamp(Vec3::splat(min_ev), Vec3::splat(max_ev)) - min_ev) / (max_ev - min_ev);
+
+    let v2 = v * v;
+    let v4 = v2 * v2;
+    let v =
+        15.5 * v4 * v2 - 40.14 * v4 * v + 31.96 * v4 - 6.868 * v2 * v + 0.4298 * v2 + 0.1191 * v
+            - 0.00232;
+
+    (outset * v).clamp(Vec3::ZERO, Vec3::ONE)
+}
+
+fn neutral(hdr: Vec3) -> Vec3 {
+    let start_compression = 0.8 - 0.04;
+    let desaturation = 0.15;
+
+    let x = hdr.min_element();
+    let offset = if x < 0.08 { x - 6.25 * x * x } else { 0.04 };
+    let c = hdr - offset;
+
+    let peak = c.max_element();
+    if peak < start_compression {
+        return c;
+    }
+
+    let d = 1.0 - start_compression;
+    let new_peak = 1.0 - d * d / (peak + d - start_compression);
+    let c = c * (new_peak / peak);
+
+    let g = 1.0 - 1.0 / (desaturation * (peak - new_peak) + 1.0);
+    c.lerp(Vec3::splat(new_peak), g)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The value of a `static const` in display.slang.
+    fn shader_const(name: &str) -> f32 {
+        let source = include_str!("../shaders/display.slang");
+        source
+            .lines()
+            .find_map(|line| {
+                let (decl, value) = line.trim().strip_prefix("static const ")?.split_once('=')?;
+                decl.trim_end()
+                    .ends_with(&format!(" {name}"))
+                    .then(|| value.trim().trim_end_matches(';').parse().ok())?
+            })
+            .unwrap_or_else(|| panic!("{name} isn't a constant in display.slang"))
+    }
+
+    #[test]
+    fn exposure_matches_shader() {
+        assert_eq!(shader_const("HISTOGRAM_BINS"), HISTOGRAM_BINS as f32);
+        assert_eq!(shader_const("MIN_LOG_LUM"), MIN_LOG_LUM);
+        assert_eq!(shader_const("MAX_LOG_LUM"), MAX_LOG_LUM);
+        assert_eq!(shader_const("LOW_PERCENTILE"), LOW_PERCENTILE);
+        assert_eq!(shader_const("HIGH_PERCENTILE"), HIGH_PERCENTILE);
+        assert_eq!(shader_const("KEY"), KEY);
+    }
+}
@@ -0,0 +1,334 @@
+use std::{f32::consts::PI, path::Path};
+
+use anyhow::Context;
+use bevy_ecs::prelude::*;
+use glam::{Vec2, Vec3, Vec4, Vec4Swizzles};
+
+use crate::{cli::Args, light::LightSource};
+
+// Radiance of the sky when no environment map or sun and sky is given.
+const SKY: Vec3 = Vec3::splat(10.0);
+
+// Resolution the sky is baked at, the sun is kept out of it so only the sky's gradients need it.
+const SKY_SIZE: (u32, u32) = (2048, 1024);
+
+// An equirectangular image lighting the scene from every direction rays escape in, see
+// environment.slang. The image's top row is straight up (+y), and rotation turns it about y.
+#[derive(Resource, Clone, Debug)]
+pub struct Environment {
+    pub width: u32,
+    pub height: u32,
+    // Radiance of each pixel row by row, with the chance of picking it per unit of image area
+    // in w so bright pixels get sampled more:
+    pub pixels: Vec<Vec4>,
+    // The cdf over each row's pixels, row by row, then the cdf over the rows:
+    pub cdf: Vec<f32>,
+    // Radians about y:
+    pub rotation: f32,
+    pub intensity: f32,
+    // Sum of each pixel's mean radiance weighted by its solid angle, and the sun's, zero for a
+    // black sky:
+    pub power: f32,
+    pub sun: Option<Sun>,
+    // Chance of sample picking the sun rather than a pixel:
+    pub sun_prob: f32,
+}
+
+// A disk of constant radiance on top of the map, sampled on its own so its size and shape don't
+// depend on the map's resolution.
+#[derive(Clone, Copy, Debug)]
+pub struct Sun {
+    // Towards the centre of the disk:
+    pub direction: Vec3,
+    pub cos_radius: f32,
+    pub solid_angle: f32,
+    pub radiance: Vec3,
+}
+
+// What the shaders need to walk the pixels and cdf buffers.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct EnvironmentInfo {
+    pub width: u32,
+    pub height: u32,
+    pub rotation: f32,
+    pub intensity: f32,
+    // Chance of next event estimation sampling the environment rather than a triangle:
+    pub light_prob: f32,
+    pub sun_prob: f32,
+    pub _pad: [u32; 2],
+    // Towards the sun with the cosine of its radius in w, and its radiance with its solid
+    // angle in w:
+    pub sun_direction: [f32; 4],
+    pub sun_radiance: [f32; 4],
+}
+
+impl Environment {
+    pub fn from_args(args: &Args) -> anyhow::Result<Self> {
+        let rotation = args.environment_rotation.unwrap_or(0.0).to_radians();
+        let intensity = args.environment_intensity.unwrap_or(1.0);
+        if let Some(path) = &args.environment {
+            return Self::load(path, rotation, intensity);
+        }
+        if let Some(sky) = args.sky() {
+            let (width, height) = SKY_SIZE;
+            let radiance = sky.bake(width, height);
+            return Ok(Self::new(width, height, radiance, rotation, intensity).with_sun(sky.sun()));
+        }
+        Ok(Self::new(1, 1, vec![SKY], rotation, intensity))
+    }
+
+    // Loads any .hdr or .exr the image crate can read.
+    pub fn load(path: &Path, rotation: f32, intensity: f32) -> anyhow::Result<Self> {
+        let image = image::open(path)
+            .with_context(|| format!("Failed to load environment map {}", path.display()))?
+            .into_rgb32f();
+        let (width, height) = image.dimensions();
+        let radiance = image.pixels().map(|p| Vec3::from(p.0)).collect();
+        tracing::info!(
+            "Loaded {}x{} environment map {}",
+            width,
+            height,
+            path.display()
+        );
+
+        Ok(Self::new(width, height, radiance, rotation, intensity))
+    }
+
+    pub fn new(
+        width: u32,
+        height: u32,
+        radiance: Vec<Vec3>,
+        rotation: f32,
+        intensity: f32,
+    ) -> Self {
+        let (w, h) = (width as usize, height as usize);
+
+        // Pixels near the poles cover less of the sphere, so weight them by sin(theta):
+        let weights = radiance
+            .iter()
+            .enumerate()
+            .map(|(i, rad)| {
+                let theta = PI * ((i / w) as f32 + 0.5) / height as f32;
+                rad.max(Vec3::ZERO).element_sum() / 3.0 * theta.sin()
+            })
+            .collect::<Vec<_>>();
+
+        let mut cdf = Vec::with_capacity(w * h + h);
+        let mut row_sums = Vec::with_capacity(h);
+        for row in weights.chunks(w) {
+            let sum: f32 = row.iter().sum();
+            let mut c = 0.0;
+            for weight in row {
+                c += weight;
+                cdf.push(if sum > 0.0 { c / sum } else { 0.0 });
+            }
+            // An all black row is never picked, but keep its cdf sane:
+            if let Some(last) = cdf.last_mut() {
+                *last = 1.0;
+            }
+            row_sums.push(sum);
+        }
+
+        let total: f32 = row_sums.iter().sum();
+        let mut c = 0.0;
+        for sum in row_sums {
+            c += sum;
+            cdf.push(if total > 0.0 { c / total } else { 0.0 });
+        }
+        if let Some(last) = cdf.last_mut() {
+            *last = 1.0;
+        }
+
+        let mean = total / (w * h) as f32;
+        let pixels = radiance
+            .iter()
+            .zip(weights)
+            .map(|(rad, weight)| rad.extend(if mean > 0.0 { weight / mean } else { 0.0 }))
+            .collect();
+
+        Self {
+            width,
+            height,
+            pixels,
+            cdf,
+            rotation,
+            intensity,
+            // Each pixel spans 2pi/w by pi/h in angle, times the sin(theta) already weighed in:
+            power: total * 2.0 * PI * PI / (w * h) as f32,
+            sun: None,
+            sun_prob: 0.0,
+        }
+    }
+
+    // Adds a sun, given in the map's own orientation so it turns with the rotation. It's picked
+    // in proportion to its power against the map's.
+    pub fn with_sun(mut self, sun: Sun) -> Self {
+        let power = sun.radiance.max(Vec3::ZERO).element_sum() / 3.0 * sun.solid_angle;
+        if power <= 0.0 {
+            return self;
+        }
+
+        let phi = sun.direction.z.atan2(sun.direction.x) + self.rotation;
+        let r = Vec2::new(sun.direction.x, sun.direction.z).length();
+        self.sun = Some(Sun {
+            direction: Vec3::new(r * phi.cos(), sun.direction.y, r * phi.sin()),
+            ..sun
+        });
+        self.sun_prob = power / (self.power + power);
+        self.power += power;
+        self
+    }
+
+    // Half and half between the environment and the triangles when there are both.
+    pub fn light_prob(&self, lights: &[LightSource]) -> f32 {
+        let triangles = lights[0].instance != LightSource::NONE.instance;
+        match (self.power > 0.0, triangles) {
+            (true, true) => 0.5,
+            (true, false) => 1.0,
+            (false, _) => 0.0,
+        }
+    }
+
+    pub fn info(&self, lights: &[LightSource]) -> EnvironmentInfo {
+        EnvironmentInfo {
+            width: self.width,
+            height: self.height,
+            rotation: self.rotation,
+            intensity: self.intensity,
+            light_prob: self.light_prob(lights),
+            sun_prob: self.sun_prob,
+            _pad: [0; 2],
+            // Nothing is ever inside a cone with a cosine past 1:
+            sun_direction: self.sun.map_or([0.0, 1.0, 0.0, 2.0], |sun| {
+                sun.direction.extend(sun.cos_radius).to_array()
+            }),
+            sun_radiance: self.sun.map_or([0.0; 4], |sun| {
+                sun.radiance.extend(sun.solid_angle).to_array()
+            }),
+        }
+    }
+
+    pub fn uv(&self, dir: Vec3) -> Vec2 {
+        let phi = dir.z.atan2(dir.x) - self.rotation;
+        Vec2::new(
+            (phi / (2.0 * PI)).rem_euclid(1.0),
+            dir.y.clamp(-1.0, 1.0).acos() / PI,
+        )
+    }
+
+    pub fn direction(&self, uv: Vec2) -> Vec3 {
+        let phi = 2.0 * PI * uv.x + self.rotation;
+        let theta = PI * uv.y;
+        Vec3::new(
+            theta.sin() * phi.cos(),
+            theta.cos(),
+            theta.sin() * phi.sin(),
+        )
+    }
+
+    fn pixel(&self, uv: Vec2) -> Vec4 {
+        let x = ((uv.x * self.width as f32) as u32).min(self.width - 1);
+        let y = ((uv.y * self.height as f32) as u32).min(self.height - 1);
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    // The sun if dir is inside its disk.
+    fn sun_at(&self, dir: Vec3) -> Option<&Sun> {
+        self.sun
+            .as_ref()
+            .filter(|sun| dir.dot(sun.direction) >= sun.cos_radius)
+    }
+
+    // Radiance arriving from the direction dir, as the miss in ray_extend.slang sees it.
+    pub fn radiance(&self, dir: Vec3) -> Vec3 {
+        let sun = self.sun_at(dir).map_or(Vec3::ZERO, |sun| sun.radiance);
+        (self.pixel(self.uv(dir)).xyz() + sun) * self.intensity
+    }
+
+    // Solid angle pdf of sample giving dir, by way of either the map or the sun.
+    pub fn pdf(&self, dir: Vec3) -> f32 {
+        let sun = self.sun_at(dir).map_or(0.0, |sun| 1.0 / sun.solid_angle);
+        (1.0 - self.sun_prob) * self.map_pdf(dir) + self.sun_prob * sun
+    }
+
+    fn map_pdf(&self, dir: Vec3) -> f32 {
+        let uv = self.uv(dir);
+        let sin_theta = (PI * uv.y).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        self.pixel(uv).w / (2.0 * PI * PI * sin_theta)
+    }
+
+    // A direction picked in proportion to how bright it is, and its solid angle pdf.
+    pub fn sample(&self, u: [f32; 4]) -> (Vec3, f32) {
+        let dir = match self.sun {
+            Some(sun) if u[0] < self.sun_prob => {
+                // Uniform over the cone the disk covers:
+                let one_minus_cos = u[1] * sun.solid_angle / (2.0 * PI);
+                let cos_theta = 1.0 - one_minus_cos;
+                let sin_theta = (one_minus_cos * (2.0 - one_minus_cos)).sqrt();
+                let phi = 2.0 * PI * u[2];
+                let (t1, t2) = sun.direction.any_orthonormal_pair();
+                (t1 * phi.cos() + t2 * phi.sin()) * sin_theta + sun.direction * cos_theta
+            }
+            _ => {
+                // Stretched back over 0..1 past the sun's share:
+                let u0 = ((u[0] - self.sun_prob) / (1.0 - self.sun_prob)).clamp(0.0, 1.0);
+                self.map_sample([u0, u[1], u[2], u[3]])
+            }
+        };
+        (dir, self.pdf(dir))
+    }
+
+    fn map_sample(&self, u: [f32; 4]) -> Vec3 {
+        let (w, h) = (self.width as usize, self.height as usize);
+        let marginal = &self.cdf[w * h..];
+        let y = marginal.partition_point(|c| *c <= u[0]).min(h - 1);
+        let conditional = &self.cdf[y * w..(y + 1) * w];
+        let x = conditional.partition_point(|c| *c <= u[1]).min(w - 1);
+
+        let uv = Vec2::new(
+            (x as f32 + u[2]) / self.width as f32,
+            (y as f32 + u[3]) / self.height as f32,
+        );
+        self.direction(uv)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    use super::*;
+    use crate::sky::Sky;
+
+    // Importance sampling the sky with its sun has to add up to the power it was picked by,
+    // which only happens if sample and pdf agree about both the map and the sun.
+    #[test]
+    fn sky_sampling_matches_power() {
+        let sky = Sky::default();
+        let environment = Environment::new(64, 32, sky.bake(64, 32), 0.7, 1.0).with_sun(sky.sun());
+        assert!(environment.sun_prob > 0.0 && environment.sun_prob < 1.0);
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let n = 1 << 16;
+        let (mut sun_hits, mut sum) = (0, 0.0);
+        for _ in 0..n {
+            let (dir, pdf) = environment.sample(rng.random());
+            sun_hits += environment.sun_at(dir).is_some() as u32;
+            sum += environment.radiance(dir).element_sum() / 3.0 / pdf;
+        }
+
+        let estimate = sum / n as f32;
+        assert!(
+            (estimate - environment.power).abs() < 0.01 * environment.power,
+            "estimated {estimate}, power {}",
+            environment.power
+        );
+        // The sun turned with the map, and is sampled about as often as it should be:
+        let sun_share = sun_hits as f32 / n as f32;
+        assert!((sun_share - environment.sun_prob).abs() < 0.01);
+    }
+}
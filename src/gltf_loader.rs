@@ -0,0 +1,384 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use bevy_ecs::prelude::*;
+use crossbeam::channel::{Receiver, TryRecvError, bounded};
+use glam::{Mat4, Vec2, Vec3, Vec4};
+use itertools::Itertools;
+
+use crate::{
+    material::{Material, MaterialId, MaterialServer},
+    mesh::{Mesh, MeshDescriptor, MeshId, MeshServer},
+    transform::Transform,
+};
+
+// A gltf file being parsed in the background, see gltf_loading_system.
+pub struct GltfLoading {
+    path: PathBuf,
+    rx: Receiver<anyhow::Result<GltfFile>>,
+}
+
+struct GltfFile {
+    document: gltf::Document,
+    buffers: Vec<gltf::buffer::Data>,
+    // Geometry of every triangle primitive in the scene, by mesh and primitive index:
+    meshes: HashMap<(usize, usize), Mesh>,
+}
+
+struct GltfContext<'a> {
+    label: String,
+    dir: PathBuf,
+    buffers: Vec<gltf::buffer::Data>,
+    mesh_server: &'a mut MeshServer,
+    material_server: &'a mut MaterialServer,
+    meshes: HashMap<(usize, usize), Mesh>,
+    mesh_ids: HashMap<(usize, usize), MeshId>,
+}
+
+// Spawns every primitive in the default scene (or the first, if there is no default) as an
+// instance with its composed node transform. The file and its geometry are read in the
+// background and count towards MeshServer::is_loading until they're in.
+pub fn load_gltf(path: &Path, mesh_server: &mut MeshServer) -> anyhow::Result<()> {
+    // Catch a bad path here rather than on another thread:
+    std::fs::metadata(path).with_context(|| format!("Failed to open {}", path.display()))?;
+
+    let (tx, rx) = bounded(1);
+    rayon::spawn({
+        let path = path.to_owned();
+        move || {
+            let file =
+                read_gltf(&path).with_context(|| format!("Failed to load {}", path.display()));
+            tx.send(file).expect("Expected to send gltf file");
+        }
+    });
+
+    mesh_server.load_gltf(GltfLoading {
+        path: path.to_owned(),
+        rx,
+    });
+    Ok(())
+}
+
+fn read_gltf(path: &Path) -> anyhow::Result<GltfFile> {
+    let gltf::Gltf { document, blob } = gltf::Gltf::open(path)?;
+    let buffers = gltf::import_buffers(&document, path.parent(), blob)?;
+
+    let mut meshes = HashMap::new();
+    for node in scene(&document)?.nodes() {
+        read_node(node, &buffers, &mut meshes)?;
+    }
+
+    Ok(GltfFile {
+        document,
+        buffers,
+        meshes,
+    })
+}
+
+fn scene(document: &gltf::Document) -> anyhow::Result<gltf::Scene<'_>> {
+    document
+        .default_scene()
+        .or_else(|| document.scenes().next())
+        .context("Expected a scene in gltf")
+}
+
+fn read_node(
+    node: gltf::Node,
+    buffers: &[gltf::buffer::Data],
+    meshes: &mut HashMap<(usize, usize), Mesh>,
+) -> anyhow::Result<()> {
+    for child in node.children() {
+        read_node(child, buffers, meshes)?;
+    }
+
+    let Some(mesh) = node.mesh() else {
+        return Ok(());
+    };
+
+    for primitive in mesh.primitives() {
+        let key = (mesh.index(), primitive.index());
+        if primitive.mode() == gltf::mesh::Mode::Triangles && !meshes.contains_key(&key) {
+            meshes.insert(key, mesh_from_primitive(&primitive, buffers)?);
+        }
+    }
+
+    Ok(())
+}
+
+// Adds the meshes and materials of gltf files that have finished parsing and spawns their
+// instances.
+pub fn gltf_loading_system(
+    mut commands: Commands,
+    mut mesh_server: ResMut<MeshServer>,
+    mut material_server: ResMut<MaterialServer>,
+) {
+    // Nothing has changed until the meshes themselves are loaded:
+    let mesh_server = mesh_server.bypass_change_detection();
+    for loading in mesh_server.take_gltf_loading() {
+        match loading.rx.try_recv() {
+            Ok(Ok(file)) => spawn_gltf(
+                &loading,
+                file,
+                &mut commands,
+                mesh_server,
+                &mut material_server,
+            ),
+            // Dropped, so a bad file doesn't keep is_loading set forever:
+            Ok(Err(e)) => tracing::warn!("{:#}", e),
+            Err(TryRecvError::Disconnected) => {
+                tracing::warn!("Gave up loading {}", loading.path.display())
+            }
+            Err(TryRecvError::Empty) => mesh_server.load_gltf(loading),
+        }
+    }
+}
+
+fn spawn_gltf(
+    loading: &GltfLoading,
+    file: GltfFile,
+    commands: &mut Commands,
+    mesh_server: &mut MeshServer,
+    material_server: &mut MaterialServer,
+) {
+    let GltfFile {
+        document,
+        buffers,
+        meshes,
+    } = file;
+    let scene = scene(&document).expect("Expected the scene that was read");
+
+    let mut ctx = GltfContext {
+        label: loading.path.to_string_lossy().into_owned(),
+        dir: loading
+            .path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default(),
+        buffers,
+        mesh_server,
+        material_server,
+        meshes,
+        mesh_ids: HashMap::new(),
+    };
+
+    for node in scene.nodes() {
+        spawn_node(&mut ctx, commands, node, Mat4::IDENTITY);
+    }
+}
+
+fn spawn_node(ctx: &mut GltfContext, commands: &mut Commands, node: gltf::Node, parent: Mat4) {
+    let matrix = parent * Mat4::from_cols_array_2d(&node.transform().matrix());
+
+    for child in node.children() {
+        spawn_node(ctx, commands, child, matrix);
+    }
+
+    let Some(mesh) = node.mesh() else {
+        return;
+    };
+
+    for primitive in mesh.primitives() {
+        if primitive.mode() != gltf::mesh::Mode::Triangles {
+            tracing::warn!(
+                "Skipping {:?} primitive {} of mesh {}",
+                primitive.mode(),
+                primitive.index(),
+                mesh.index()
+            );
+            continue;
+        }
+
+        let key = (mesh.index(), primitive.index());
+        let mesh_id = match ctx.mesh_ids.get(&key) {
+            Some(id) => *id,
+            None => {
+                let data = ctx
+                    .meshes
+                    .remove(&key)
+                    .expect("Expected every primitive to have been read");
+                let id = ctx.mesh_server.add_mesh(
+                    MeshDescriptor::GLTF {
+                        path: ctx.label.clone(),
+                        mesh: mesh.index(),
+                        primitive: primitive.index(),
+                    },
+                    data,
+                );
+                ctx.mesh_ids.insert(key, id);
+                id
+            }
+        };
+
+        let gltf_material = primitive.material();
+        let material_label = match gltf_material.index() {
+            Some(i) => format!("{}#material{}", ctx.label, i),
+            None => format!("{}#default", ctx.label),
+        };
+        let material = material_from_gltf(
+            &gltf_material,
+            &ctx.label,
+            &ctx.dir,
+            &ctx.buffers,
+            ctx.material_server,
+        );
+        let material_id: MaterialId = ctx
+            .material_server
+            .add_material_labelled(material, material_label);
+
+        commands.spawn((Transform::from_matrix(matrix), mesh_id, material_id));
+    }
+}
+
+// Loads a single primitive on its own, for when a mesh is referenced without its scene.
+pub fn load_primitive(path: &str, mesh: usize, primitive: usize) -> anyhow::Result<Mesh> {
+    let path = Path::new(path);
+    let gltf::Gltf { document, blob } = gltf::Gltf::open(path)?;
+    let buffers = gltf::import_buffers(&document, path.parent(), blob)?;
+
+    let primitive = document
+        .meshes()
+        .nth(mesh)
+        .and_then(|m| m.primitives().nth(primitive))
+        .with_context(|| format!("No primitive {primitive} in mesh {mesh}"))?;
+
+    mesh_from_primitive(&primitive, &buffers)
+}
+
+fn mesh_from_primitive(
+    primitive: &gltf::Primitive,
+    buffers: &[gltf::buffer::Data],
+) -> anyhow::Result<Mesh> {
+    let reader = primitive.reader(|buffer| Some(buffers[buffer.index()].0.as_slice()));
+
+    let positions = reader
+        .read_positions()
+        .context("Expected primitive to have positions")?
+        .map(|p| Vec3::from(p).extend(1.0))
+        .collect_vec();
+
+    let indices = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect_vec(),
+        None => (0..positions.len() as u32).collect_vec(),
+    };
+
+    // Missing normals get computed by the mesh:
+    let normals = reader
+        .read_normals()
+        .map(|n| n.map(|n| Vec3::from(n).extend(0.0)).collect_vec())
+        .unwrap_or_default();
+
+    let uvs = reader
+        .read_tex_coords(0)
+        .map(|t| t.into_f32().map(Vec2::from).collect_vec())
+        .unwrap_or_default();
+
+    // Missing tangents get generated by the mesh, as the spec asks for MikkTSpace ones:
+    let tangents = reader
+        .read_tangents()
+        .map(|t| t.map(Vec4::from).collect_vec())
+        .unwrap_or_default();
+
+    Ok(Mesh::new(positions, indices, normals, uvs, tangents))
+}
+
+// Based on https://registry.khronos.org/glTF/specs/2.0/glTF-2.0.html#materials
+fn material_from_gltf(
+    material: &gltf::Material,
+    label: &str,
+    dir: &Path,
+    buffers: &[gltf::buffer::Data],
+    material_server: &mut MaterialServer,
+) -> Material {
+    let pbr = material.pbr_metallic_roughness();
+    let emissive =
+        Vec3::from(material.emissive_factor()) * material.emissive_strength().unwrap_or(1.0);
+
+    let mut texture = |texture: Option<gltf::Texture>| {
+        texture.map_or(0, |t| {
+            texture_from_gltf(t, label, dir, buffers, material_server)
+        })
+    };
+
+    Material {
+        colour_texture: texture(pbr.base_color_texture().map(|t| t.texture())),
+        emissive_texture: texture(material.emissive_texture().map(|t| t.texture())),
+        metallic_roughness_texture: texture(pbr.metallic_roughness_texture().map(|t| t.texture())),
+        normal_texture: texture(material.normal_texture().map(|t| t.texture())),
+        colour: Vec4::from(pbr.base_color_factor()),
+        emissive: emissive.extend(0.0),
+        metallic: pbr.metallic_factor(),
+        roughness: pbr.roughness_factor(),
+        ior: material.ior().unwrap_or(1.5),
+        transmission: material
+            .transmission()
+            .map(|t| t.transmission_factor())
+            .unwrap_or(0.0),
+    }
+}
+
+// Images stored next to the gltf are loaded by path. Ones embedded in it, in a buffer view of a
+// glb or as a data uri, are handed to the texture server as the bytes they're encoded as.
+fn texture_from_gltf(
+    texture: gltf::Texture,
+    label: &str,
+    dir: &Path,
+    buffers: &[gltf::buffer::Data],
+    material_server: &mut MaterialServer,
+) -> u32 {
+    let image = texture.source();
+    if let gltf::image::Source::Uri { uri, .. } = image.source()
+        && !uri.starts_with("data:")
+    {
+        return material_server.add_texture(dir.join(percent_decode(uri)));
+    }
+
+    let embedded = || match image.source() {
+        gltf::image::Source::View { view, .. } => buffers[view.buffer().index()]
+            .get(view.offset()..view.offset() + view.length())
+            .map(<[u8]>::to_vec)
+            .context("Image buffer view is out of bounds"),
+        // Decoding the base64 of a data uri is all this does without a base path:
+        gltf::image::Source::Uri { uri, .. } => {
+            Ok(gltf::buffer::Data::from_source(gltf::buffer::Source::Uri(uri), None)?.0)
+        }
+    };
+
+    material_server
+        .add_texture_embedded(format!("{}#image{}", label, image.index()), embedded)
+        .unwrap_or_else(|e| {
+            tracing::warn!(
+                "Skipping image {} of texture {}: {:#}",
+                image.index(),
+                texture.index(),
+                e
+            );
+            0
+        })
+}
+
+// Uris in a gltf are percent encoded, so a space in a file name is stored as %20.
+fn percent_decode(uri: &str) -> String {
+    let bytes = uri.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .filter(|h| h.iter().all(u8::is_ascii_hexdigit));
+        match (bytes[i], hex) {
+            (b'%', Some(hex)) => {
+                let hex = std::str::from_utf8(hex).expect("Expected hex digits to be ascii");
+                decoded.push(u8::from_str_radix(hex, 16).expect("Expected hex digits"));
+                i += 3;
+            }
+            (b, _) => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
@@ -0,0 +1,134 @@
+use std::{path::PathBuf, time::Instant};
+
+use bevy_ecs::prelude::*;
+
+use crate::{
+    app::BevyApp,
+    cli::{Args, is_hdr_output},
+    delta_time::DeltaTime,
+    pathtracer::{Pathtracer, PathtracerOutput, save_render},
+    pathtracer_state::PathtracerState,
+    render_resources::{RenderDevice, RenderQueue},
+    winnit::{WinitDeviceEvent, WinitWindowEvent},
+};
+
+// How many frames to run between checks of the sample counter, reading it back stalls the gpu.
+const FRAMES_PER_CHECK: u32 = 16;
+
+// Drives the app without a window until the primary pathtracer has taken enough samples,
+// then writes its output to disk.
+pub struct HeadlessApp {
+    bevy_app: BevyApp,
+    output: PathBuf,
+}
+
+impl HeadlessApp {
+    pub fn new(mut bevy_app: BevyApp, output: PathBuf) -> Self {
+        // Nothing writes these without winit, but the input systems still expect them.
+        bevy_app.world.init_resource::<Messages<WinitWindowEvent>>();
+        bevy_app.world.init_resource::<Messages<WinitDeviceEvent>>();
+
+        Self { bevy_app, output }
+    }
+
+    pub fn run(mut self) -> anyhow::Result<()> {
+        let start = Instant::now();
+        let mut time = Instant::now();
+        let mut frame = 0;
+
+        loop {
+            self.bevy_app
+                .world
+                .insert_resource(DeltaTime(time.elapsed().as_secs_f64()));
+            time = Instant::now();
+
+            self.bevy_app.run();
+            frame += 1;
+
+            if frame % FRAMES_PER_CHECK != 0 {
+                continue;
+            }
+
+            let Some((spp, target)) = self.samples_per_pixel()? else {
+                continue;
+            };
+            tracing::info!(
+                "{:.1}/{} samples per pixel after {:.1}s",
+                spp,
+                target,
+                start.elapsed().as_secs_f64()
+            );
+
+            if spp >= target as f64 {
+                break;
+            }
+        }
+
+        self.save()
+    }
+
+    // Samples per pixel taken so far and the target, if there is a primary pathtracer yet.
+    fn samples_per_pixel(&mut self) -> anyhow::Result<Option<(f64, u32)>> {
+        let device = self.bevy_app.world.resource::<RenderDevice>().0.clone();
+        let queue = self.bevy_app.world.resource::<RenderQueue>().0.clone();
+
+        let mut query = self
+            .bevy_app
+            .world
+            .query::<(&Pathtracer, &PathtracerState)>();
+        let Some((pt, pts)) = query
+            .iter(&self.bevy_app.world)
+            .find(|(pt, _)| pt.is_primary)
+        else {
+            return Ok(None);
+        };
+
+        // Samples are counted when spawned, the ones still in flight haven't landed yet.
+        let spawned = pts.samples_spawned(&device, &queue)?;
+        let finished = spawned.saturating_sub(pt.threads as u64);
+
+        let spp = finished as f64 / pt.region().area() as f64;
+        Ok(Some((spp, pt.samples_per_pixel)))
+    }
+
+    fn save(&mut self) -> anyhow::Result<()> {
+        let device = self.bevy_app.world.resource::<RenderDevice>().0.clone();
+        let queue = self.bevy_app.world.resource::<RenderQueue>().0.clone();
+
+        let mut query = self
+            .bevy_app
+            .world
+            .query::<(&Pathtracer, &PathtracerOutput)>();
+        let Some((pt, pto)) = query
+            .iter(&self.bevy_app.world)
+            .find(|(pt, _)| pt.is_primary)
+        else {
+            anyhow::bail!("No primary pathtracer to save output from");
+        };
+        let args = self.bevy_app.world.resource::<Args>();
+        let merge_into = args.merge_into.as_deref();
+
+        if is_hdr_output(&self.output) {
+            save_render(
+                pto.read_radiance(&device, &queue)?,
+                &self.output,
+                pt.crop,
+                args.cropped_output,
+                merge_into,
+                |i| i.into_rgb32f(),
+            )?;
+        } else {
+            save_render(
+                pto.read_image(&device, &queue)?,
+                &self.output,
+                pt.crop,
+                args.cropped_output,
+                merge_into,
+                |i| i.into_rgba8(),
+            )?;
+        }
+        tracing::info!("Wrote {}", self.output.display());
+
+        Ok(())
+    }
+}
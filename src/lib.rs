@@ -1,22 +1,40 @@
+use anyhow::Context;
+use clap::Parser;
 use winit::event_loop::EventLoop;
 
-use crate::{app::BevyApp, winnit::WinitApp};
+use crate::{
+    app::BevyApp,
+    cli::{Args, Mode},
+    cpu::CpuApp,
+    environment::Environment,
+    headless::HeadlessApp,
+    scene_file::SceneFile,
+    winnit::WinitApp,
+};
 
 mod app;
 mod binder;
 mod blas;
 mod bvh;
 mod camera;
+mod cli;
+mod cpu;
 mod dielectric;
 mod dims;
+mod display;
 mod emissive;
+mod environment;
 // mod extension;
+mod gltf_loader;
+mod headless;
 mod instance;
 mod lambertian;
+mod light;
 // mod logic;
 mod material;
 mod mesh;
 mod metallic;
+mod obj_loader;
 // mod new_ray;
 mod path;
 mod pathtracer;
@@ -24,32 +42,95 @@ mod pathtracer_manager;
 mod queue;
 mod render;
 mod render_resources;
+mod scene_file;
 mod scenes;
 // mod shadow;
 mod delta_time;
 mod pathtracer_state;
 mod schedule;
+mod sky;
 mod texture;
 mod threadpool;
 mod tlas;
 mod transform;
 mod winnit;
 
-pub fn run() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
-
+// Just the scene and what it needs to load, none of which touches the gpu.
+fn build_scene(mut args: Args) -> anyhow::Result<BevyApp> {
     let mut bevy_app = BevyApp::new();
 
+    // A gltf or obj given directly is loaded by a startup system, which can't fail the run:
+    if let Some(path) = &args.scene {
+        std::fs::metadata(path)
+            .with_context(|| format!("Failed to open scene {}", path.display()))?;
+    }
+
+    let scene_file = args.scene.as_ref().filter(|p| {
+        p.extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("json"))
+    });
+    if let Some(path) = scene_file {
+        let scene = SceneFile::load(path)?;
+        scene.check_assets()?;
+        args.apply_settings(&scene.settings);
+        bevy_app.world.insert_resource(scene);
+    }
+    if let Some(crop) = args.crop
+        && !crop.fits((args.width(), args.height()))
+    {
+        anyhow::bail!(
+            "Crop {},{},{},{} isn't inside the {}x{} frame",
+            crop.x,
+            crop.y,
+            crop.width,
+            crop.height,
+            args.width(),
+            args.height()
+        );
+    }
+    bevy_app.world.insert_resource(args.bvh_settings());
+    bevy_app
+        .world
+        .insert_resource(Environment::from_args(&args)?);
+    bevy_app.world.insert_resource(args);
+
     threadpool::initialize(&mut bevy_app);
-    render_resources::initialize(&mut bevy_app);
-    render::initialize(&mut bevy_app);
-    pathtracer::initialize(&mut bevy_app);
     mesh::initialize(&mut bevy_app);
     material::initialize(&mut bevy_app);
+    texture::initialize(&mut bevy_app);
     scenes::initialize(&mut bevy_app);
+
+    Ok(bevy_app)
+}
+
+fn build_app(args: Args) -> anyhow::Result<BevyApp> {
+    let mut bevy_app = build_scene(args)?;
+
+    render_resources::initialize(&mut bevy_app);
+    pathtracer::initialize(&mut bevy_app);
     binder::initialize(&mut bevy_app);
     pathtracer_manager::initialize(&mut bevy_app);
     camera::initialize(&mut bevy_app);
+    display::initialize(&mut bevy_app);
+
+    Ok(bevy_app)
+}
+
+pub fn run() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let args = Args::parse();
+    match args.mode {
+        Mode::Interactive => run_interactive(args),
+        Mode::Headless => run_headless(args),
+        Mode::Cpu => run_cpu(args),
+    }
+}
+
+fn run_interactive(args: Args) -> anyhow::Result<()> {
+    let mut bevy_app = build_app(args)?;
+    // Presenting to the surface only makes sense with a window.
+    render::initialize(&mut bevy_app);
 
     let event_loop = EventLoop::new()?;
     let mut app = WinitApp::new(bevy_app);
@@ -57,3 +138,18 @@ pub fn run() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+// Renders without opening a window, stopping once the primary pathtracer has taken its target
+// samples per pixel and writing the result to the output path.
+fn run_headless(args: Args) -> anyhow::Result<()> {
+    let output = args.output.clone();
+    let bevy_app = build_app(args)?;
+    HeadlessApp::new(bevy_app, output).run()
+}
+
+// Renders the same scene with the cpu reference path tracer, for machines without a gpu.
+fn run_cpu(args: Args) -> anyhow::Result<()> {
+    let output = args.output.clone();
+    let bevy_app = build_scene(args)?;
+    CpuApp::new(bevy_app, output).run()
+}
@@ -0,0 +1,104 @@
+use glam::Vec4Swizzles;
+use itertools::Itertools;
+
+use crate::{instance::Instance, material::Material, mesh::MeshServer, transform::Transform};
+
+// An emissive triangle that can be picked for next event estimation, see sampleLight in
+// shade.slang. Triangles are picked in proportion to their emitted power.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, Default)]
+pub struct LightSource {
+    pub instance: u32,
+    pub triangle: u32,
+    // Probability of picking this triangle, and of picking it or any before it:
+    pub prob: f32,
+    pub cdf: f32,
+    // Emitted power of all the lights together, the same in every entry:
+    pub total: f32,
+}
+
+impl LightSource {
+    // Bound in place of the list when there are no lights, so the buffer is never empty.
+    pub const NONE: Self = Self {
+        instance: u32::MAX,
+        triangle: u32::MAX,
+        prob: 0.0,
+        cdf: 1.0,
+        total: 0.0,
+    };
+}
+
+// Every triangle of every emissive instance, indexed the same way as the instances, transforms
+// and materials about to be bound.
+pub fn light_sources(
+    instances: &[Instance],
+    transforms: &[Transform],
+    materials: &[Material],
+    mesh_server: &MeshServer,
+) -> Vec<LightSource> {
+    let geometries = mesh_server.geometries().collect_vec();
+
+    let mut lights = Vec::new();
+    let mut powers = Vec::new();
+    for (i, instance) in instances.iter().enumerate() {
+        let emissive = materials[instance.material_idx as usize].emissive.xyz();
+        let radiance = emissive.element_sum() / 3.0;
+        if radiance <= 0.0 {
+            continue;
+        }
+
+        let Some(geometry) = geometries.get(instance.geometry_idx as usize) else {
+            continue;
+        };
+        let m = transforms[instance.transform_idx as usize].matrix();
+        let mesh = &geometry.mesh;
+
+        for (t, face) in mesh.faces.iter().enumerate() {
+            let [p0, p1, p2] = [face.x, face.y, face.z]
+                .map(|v| m.transform_point3(mesh.positions[v as usize].xyz()));
+            let area = (p1 - p0).cross(p2 - p0).length() / 2.0;
+            if area <= 0.0 {
+                continue;
+            }
+
+            lights.push(LightSource {
+                instance: i as u32,
+                triangle: t as u32,
+                ..Default::default()
+            });
+            powers.push(area * radiance);
+        }
+    }
+
+    let total: f32 = powers.iter().sum();
+    if lights.is_empty() || total <= 0.0 {
+        return vec![LightSource::NONE];
+    }
+
+    let mut cdf = 0.0;
+    for (light, power) in lights.iter_mut().zip(powers) {
+        light.prob = power / total;
+        cdf += light.prob;
+        light.cdf = cdf;
+        light.total = total;
+    }
+    // Make sure the last light catches anything rounding leaves past the end:
+    if let Some(last) = lights.last_mut() {
+        last.cdf = 1.0;
+    }
+
+    lights
+}
+
+// Picks the light whose cdf range contains u, for u in 0..1.
+pub fn pick_light(lights: &[LightSource], u: f32) -> &LightSource {
+    let i = lights.partition_point(|l| l.cdf <= u);
+    &lights[i.min(lights.len() - 1)]
+}
+
+// Uniform point on a triangle from two uniform numbers, as the barycentric weights of its
+// second and third vertices.
+pub fn triangle_sample(u: f32, v: f32) -> (f32, f32) {
+    let su = u.sqrt();
+    (su * v, su * (1.0 - v))
+}
@@ -1,16 +1,26 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 
 use bevy_ecs::prelude::*;
 use glam::Vec4;
 
-use crate::app::BevyApp;
+use crate::{app::BevyApp, texture::TextureSource};
 
 pub fn initialize(app: &mut BevyApp) {
     app.world.insert_resource(MaterialServer::default());
 }
 
 #[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, Component)]
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    bytemuck::Pod,
+    bytemuck::Zeroable,
+    Component,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(default)]
 pub struct Material {
     pub colour_texture: u32,             // 0 -> use base colour
     pub emissive_texture: u32,           // 0 -> use base emissive
@@ -48,6 +58,10 @@ pub struct MaterialId(usize);
 pub struct MaterialServer {
     materials: Vec<Material>,
     by_label: HashMap<String, MaterialId>,
+    // Images referenced by the materials' texture slots, slot n is textures[n - 1]:
+    textures: Vec<TextureSource>,
+    // By path, or by label for embedded images:
+    texture_by_key: HashMap<String, u32>,
 }
 
 impl MaterialServer {
@@ -68,6 +82,40 @@ impl MaterialServer {
     pub fn get(&self, id: MaterialId) -> Option<&Material> {
         self.materials.get(id.0)
     }
+
+    pub fn textures(&self) -> &[TextureSource] {
+        &self.textures
+    }
+
+    // Returns the index to put in a material's texture slot, 0 is kept for no texture.
+    pub fn add_texture(&mut self, path: PathBuf) -> u32 {
+        let key = path.to_string_lossy().into_owned();
+        if let Some(index) = self.texture_by_key.get(&key) {
+            return *index;
+        }
+        self.push_texture(key, TextureSource::Path(path))
+    }
+
+    // Same as add_texture for an image that isn't a file of its own, like one embedded in a glb.
+    // The encoded bytes are only fetched the first time the label is seen.
+    pub fn add_texture_embedded(
+        &mut self,
+        label: String,
+        bytes: impl FnOnce() -> anyhow::Result<Vec<u8>>,
+    ) -> anyhow::Result<u32> {
+        if let Some(index) = self.texture_by_key.get(&label) {
+            return Ok(*index);
+        }
+        let bytes = Arc::from(bytes()?);
+        Ok(self.push_texture(label.clone(), TextureSource::Embedded { label, bytes }))
+    }
+
+    fn push_texture(&mut self, key: String, source: TextureSource) -> u32 {
+        self.textures.push(source);
+        let index = self.textures.len() as u32;
+        self.texture_by_key.insert(key, index);
+        index
+    }
 }
 
 // use wesl::include_wesl;
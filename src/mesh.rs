@@ -1,24 +1,33 @@
 use std::{collections::HashMap, sync::Arc};
 
 use bevy_ecs::prelude::*;
-use crossbeam::channel::bounded;
-use glam::{UVec3, UVec4, Vec3, Vec4, Vec4Swizzles};
+use crossbeam::channel::{TryRecvError, bounded};
+use glam::{UVec3, UVec4, Vec2, Vec3, Vec4, Vec4Swizzles};
 use itertools::Itertools;
 use wgpu::util::DeviceExt;
 
 use crate::{
     app::BevyApp,
     blas::BLAS,
-    bvh::{AABB, BVH, BVHNodeGPU},
+    bvh::{AABB, BVH, BVHNodeGPU, BVHSettings, BuildMethod},
+    gltf_loader::{self, GltfLoading},
+    obj_loader::{self, ObjLoading},
     render_resources::RenderDevice,
     schedule::{self},
 };
 
 pub fn initialize(app: &mut BevyApp) {
     app.world.insert_resource(MeshServer::default());
-    app.world
-        .get_resource_or_init::<Schedules>()
-        .add_systems(schedule::Update, mesh_loading_system);
+    app.world.get_resource_or_init::<Schedules>().add_systems(
+        schedule::Update,
+        (
+            obj_loader::obj_loading_system,
+            gltf_loader::gltf_loading_system,
+            mesh_loading_system
+                .after(obj_loader::obj_loading_system)
+                .after(gltf_loader::gltf_loading_system),
+        ),
+    );
 }
 
 #[derive(Default, Debug, Clone)]
@@ -26,7 +35,10 @@ pub struct Mesh {
     pub positions: Vec<Vec4>,
     pub normals: Vec<Vec4>,
     pub faces: Vec<UVec4>,
-    // pub uv: Vec<UVec2>,
+    // Texture coordinates with the origin at the image's top left, empty if there are none:
+    pub uvs: Vec<Vec2>,
+    // Tangents along +u with the bitangent's handedness in w, zero where there are no uvs:
+    pub tangents: Vec<Vec4>,
 }
 
 #[repr(C)]
@@ -43,18 +55,44 @@ pub struct GPUVertexData {
     position: Vec4,
     normal: Vec4,
     uv: Vec4,
+    tangent: Vec4,
 }
 
 #[derive(Clone, Copy, Component, Debug, Eq, PartialEq, Hash)]
 pub struct MeshId(usize);
 
-#[derive(Hash, Clone, PartialEq, Eq)]
+#[derive(Hash, Clone, PartialEq, Eq, Debug, serde::Serialize, serde::Deserialize)]
 pub enum MeshDescriptor {
-    TOBJ(String),
+    TOBJ {
+        path: String,
+        #[serde(default)]
+        model: usize,
+        #[serde(default)]
+        normalise: Normalise,
+    },
+    GLTF {
+        path: String,
+        mesh: usize,
+        primitive: usize,
+    },
     Rect,
     Cube,
 }
 
+// How to fit loaded geometry to the unit cube, if at all.
+#[derive(
+    Hash, Clone, Copy, PartialEq, Eq, Debug, Default, serde::Serialize, serde::Deserialize,
+)]
+pub enum Normalise {
+    // Keep the coordinates from the file
+    #[default]
+    None,
+    // Centre and scale evenly so the longest axis spans -1..1
+    Uniform,
+    // Centre and scale every axis to span -1..1, stretching the mesh
+    PerAxis,
+}
+
 pub struct MeshData {
     pub nodes: Vec<BVHNodeGPU>,
     pub mesh: Mesh,
@@ -64,12 +102,17 @@ pub struct MeshData {
 pub struct MeshLoading {
     descriptor: MeshDescriptor,
     id: MeshId,
-    rx: Option<crossbeam::channel::Receiver<MeshData>>,
+    // Already built geometry, skips loading from the descriptor:
+    mesh: Option<Mesh>,
+    rx: Option<crossbeam::channel::Receiver<anyhow::Result<MeshData>>>,
 }
 
 #[derive(Resource, Default)]
 pub struct MeshServer {
     loading: Vec<MeshLoading>,
+    // Obj files still being parsed, their meshes are added when they're done:
+    obj_loading: Vec<ObjLoading>,
+    gltf_loading: Vec<GltfLoading>,
     data: Vec<Option<MeshData>>,
     counter: usize,
     by_desc: HashMap<MeshDescriptor, MeshId>,
@@ -81,55 +124,88 @@ pub struct MeshServer {
     mesh_id_to_geom_id: HashMap<usize, u32>,
 }
 
-fn mesh_loading_system(mut mesh_server: ResMut<MeshServer>, device: Res<RenderDevice>) {
+fn mesh_loading_system(
+    mut mesh_server: ResMut<MeshServer>,
+    device: Option<Res<RenderDevice>>,
+    bvh_settings: Res<BVHSettings>,
+) {
     let MeshServer { loading, data, .. } = mesh_server.bypass_change_detection();
 
     let mut changed = false;
     loading.retain_mut(|l| {
         if let Some(rx) = &l.rx {
-            if let Ok(d) = rx.try_recv() {
-                data[l.id.0] = Some(d);
-                changed = true;
-                false
-            } else {
-                true
+            match rx.try_recv() {
+                Ok(Ok(d)) => {
+                    data[l.id.0] = Some(d);
+                    changed = true;
+                    false
+                }
+                // Left without data, instances of it are skipped when binding:
+                Ok(Err(e)) => {
+                    tracing::warn!("Failed to load mesh {:?}: {:#}", l.descriptor, e);
+                    false
+                }
+                Err(TryRecvError::Disconnected) => {
+                    tracing::warn!("Gave up loading mesh {:?}", l.descriptor);
+                    false
+                }
+                Err(TryRecvError::Empty) => true,
             }
         } else {
-            l.start();
+            l.start(bvh_settings.blas);
             true
         }
     });
 
     if changed {
-        mesh_server.regenerate_buffer(device.0.clone());
+        mesh_server.regenerate_buffer(device.map(|d| d.0.clone()));
         mesh_server.set_changed();
     }
 }
 
+fn load_descriptor(descriptor: &MeshDescriptor) -> anyhow::Result<Mesh> {
+    Ok(match descriptor {
+        MeshDescriptor::TOBJ {
+            path,
+            model,
+            normalise,
+        } => obj_loader::load_model(path, *model, *normalise)?,
+        MeshDescriptor::GLTF {
+            path,
+            mesh,
+            primitive,
+        } => gltf_loader::load_primitive(path, *mesh, *primitive)?,
+        MeshDescriptor::Rect => Mesh::rect(),
+        MeshDescriptor::Cube => Mesh::cube(),
+    })
+}
+
 impl MeshLoading {
-    fn start(&mut self) {
+    fn start(&mut self, method: BuildMethod) {
         if self.rx.is_some() {
             return;
         }
 
-        let (tx, rx) = bounded::<MeshData>(1);
+        let (tx, rx) = bounded::<anyhow::Result<MeshData>>(1);
         self.rx = Some(rx);
 
         rayon::spawn({
             // let device = device.clone();
             let descriptor = self.descriptor.clone();
+            let mesh = self.mesh.take();
             move || {
-                let mut load_options = tobj::GPU_LOAD_OPTIONS;
-                load_options.single_index = false;
-                let mesh = match &descriptor {
-                    MeshDescriptor::TOBJ(s) => {
-                        Mesh::from_model(&tobj::load_obj(s, &load_options).unwrap().0[0].mesh)
-                    }
-                    MeshDescriptor::Rect => Mesh::rect(),
-                    MeshDescriptor::Cube => Mesh::cube(),
+                let mesh = match mesh {
+                    Some(mesh) => mesh,
+                    None => match load_descriptor(&descriptor) {
+                        Ok(mesh) => mesh,
+                        Err(e) => {
+                            tx.send(Err(e)).expect("Expected to send mesh data");
+                            return;
+                        }
+                    },
                 };
 
-                let blas = BLAS::new(mesh);
+                let blas = BLAS::new(mesh, &method);
                 let aabb = blas.node_bounds(0);
                 let mesh = blas.mesh;
                 let nodes = blas
@@ -138,7 +214,7 @@ impl MeshLoading {
                     .map(|node| BVHNodeGPU::from(node))
                     .collect_vec();
 
-                tx.send(MeshData { nodes, mesh, aabb })
+                tx.send(Ok(MeshData { nodes, mesh, aabb }))
                     .expect("Expected to send mesh data");
             }
         });
@@ -147,6 +223,16 @@ impl MeshLoading {
 
 impl MeshServer {
     pub fn load_mesh(&mut self, descriptor: MeshDescriptor) -> MeshId {
+        self.load(descriptor, None)
+    }
+
+    // Registers geometry that has already been built, the descriptor identifies where it
+    // came from so it is still only loaded once.
+    pub fn add_mesh(&mut self, descriptor: MeshDescriptor, mesh: Mesh) -> MeshId {
+        self.load(descriptor, Some(mesh))
+    }
+
+    fn load(&mut self, descriptor: MeshDescriptor, mesh: Option<Mesh>) -> MeshId {
         if let Some(id) = self.by_desc.get(&descriptor) {
             return *id;
         }
@@ -157,6 +243,7 @@ impl MeshServer {
         self.loading.push(MeshLoading {
             descriptor: descriptor.clone(),
             id,
+            mesh,
             rx: None,
         });
 
@@ -164,6 +251,26 @@ impl MeshServer {
         id
     }
 
+    pub fn load_obj(&mut self, loading: ObjLoading) {
+        self.obj_loading.push(loading);
+    }
+
+    pub fn take_obj_loading(&mut self) -> Vec<ObjLoading> {
+        std::mem::take(&mut self.obj_loading)
+    }
+
+    pub fn load_gltf(&mut self, loading: GltfLoading) {
+        self.gltf_loading.push(loading);
+    }
+
+    pub fn take_gltf_loading(&mut self) -> Vec<GltfLoading> {
+        std::mem::take(&mut self.gltf_loading)
+    }
+
+    pub fn is_loading(&self) -> bool {
+        !self.loading.is_empty() || !self.obj_loading.is_empty() || !self.gltf_loading.is_empty()
+    }
+
     pub fn mesh_data(&self, id: MeshId) -> Option<&MeshData> {
         if id.0 >= self.data.len() {
             return None;
@@ -195,7 +302,13 @@ impl MeshServer {
         self.mesh_id_to_geom_id.get(&id.0).copied()
     }
 
-    pub fn regenerate_buffer(&mut self, device: Arc<wgpu::Device>) {
+    // Loaded meshes in geometry id order, the same order they are packed into the buffers.
+    pub fn geometries(&self) -> impl Iterator<Item = &MeshData> {
+        self.data.iter().flatten()
+    }
+
+    // Without a device (rendering on the cpu) only the geometry ids and bounds are updated.
+    pub fn regenerate_buffer(&mut self, device: Option<Arc<wgpu::Device>>) {
         let mut vertices = Vec::new();
         let mut indices = Vec::new();
         let mut nodes = Vec::new();
@@ -215,6 +328,8 @@ impl MeshServer {
                 positions,
                 normals,
                 faces,
+                uvs,
+                tangents,
             } = mesh_data.mesh.clone();
 
             // Map the mesh id to geometry id for packing:
@@ -235,10 +350,17 @@ impl MeshServer {
                 positions
                     .into_iter()
                     .zip(normals)
-                    .map(|(position, normal)| GPUVertexData {
+                    .enumerate()
+                    .map(|(i, (position, normal))| GPUVertexData {
                         position,
                         normal,
-                        uv: Vec4::ZERO,
+                        uv: uvs
+                            .get(i)
+                            .copied()
+                            .unwrap_or_default()
+                            .extend(0.0)
+                            .extend(0.0),
+                        tangent: tangents.get(i).copied().unwrap_or_default(),
                     })
                     .collect_vec()
                     .as_slice(),
@@ -250,6 +372,10 @@ impl MeshServer {
 
         self.aabbs = aabbs;
 
+        let Some(device) = device else {
+            return;
+        };
+
         self.node_buffer = Some(
             device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: Some("Mesh BVHNode Buffer"),
@@ -285,7 +411,13 @@ impl MeshServer {
 }
 
 impl Mesh {
-    pub fn new(positions: Vec<Vec4>, indices: Vec<u32>, normals: Vec<Vec4>) -> Self {
+    pub fn new(
+        positions: Vec<Vec4>,
+        indices: Vec<u32>,
+        normals: Vec<Vec4>,
+        uvs: Vec<Vec2>,
+        tangents: Vec<Vec4>,
+    ) -> Self {
         let faces = indices
             .chunks_exact(3)
             .into_iter()
@@ -298,10 +430,18 @@ impl Mesh {
             Self::compute_vertex_normals_ccw(&positions, &indices)
         };
 
+        let tangents = if tangents.len() >= positions.len() && !tangents.is_empty() {
+            tangents
+        } else {
+            Self::compute_vertex_tangents(&positions, &normals, &uvs, &faces)
+        };
+
         Self {
             positions,
             normals,
             faces,
+            uvs,
+            tangents,
         }
     }
 
@@ -309,26 +449,7 @@ impl Mesh {
         let positions = model
             .positions
             .chunks_exact(3)
-            .map(|chunk| Vec3::from_slice(chunk).extend(0.0))
-            .collect_vec();
-
-        let len = positions.len();
-        let center: Vec4 = positions.iter().sum::<Vec4>() / (len as f32);
-
-        let positions = positions.into_iter().map(|p| p - center).collect_vec();
-
-        // Calculate the greatest distance from center
-        // so we can scale down such that furthest point is on the unit cube
-        let extent: Vec4 = positions
-            .iter()
-            .copied()
-            .reduce(|acc, p| p.max(acc))
-            .unwrap_or_default();
-
-        let positions = positions
-            .into_iter()
-            .map(|p| p.xyz() / extent.xyz())
-            .map(|p| p.extend(1.0))
+            .map(|chunk| Vec3::from_slice(chunk).extend(1.0))
             .collect_vec();
 
         let faces = model
@@ -347,10 +468,59 @@ impl Mesh {
             Self::compute_vertex_normals_ccw(&positions, &model.indices)
         };
 
+        // Obj puts the origin at the bottom left:
+        let uvs = model
+            .texcoords
+            .chunks_exact(2)
+            .map(|c| Vec2::new(c[0], 1.0 - c[1]))
+            .collect_vec();
+        let tangents = Self::compute_vertex_tangents(&positions, &normals, &uvs, &faces);
+
         Self {
             positions,
             normals,
             faces,
+            uvs,
+            tangents,
+        }
+    }
+
+    pub fn aabb(&self) -> AABB {
+        let lb = self
+            .positions
+            .iter()
+            .fold(Vec3::INFINITY, |acc, p| acc.min(p.xyz()));
+        let ub = self
+            .positions
+            .iter()
+            .fold(Vec3::NEG_INFINITY, |acc, p| acc.max(p.xyz()));
+        AABB { lb, ub }
+    }
+
+    // Centres `bounds` on the origin and scales it into the -1..1 cube. Bounds are passed in
+    // so the parts of a multi-part asset can share them and stay assembled.
+    pub fn normalise(&mut self, normalise: Normalise, bounds: AABB) {
+        let centre = (bounds.lb + bounds.ub) / 2.0;
+        let half = (bounds.ub - bounds.lb) / 2.0;
+
+        let scale = match normalise {
+            Normalise::None => return,
+            Normalise::Uniform if half.max_element() > 0.0 => Vec3::splat(1.0 / half.max_element()),
+            Normalise::Uniform => Vec3::ONE,
+            // Flat axes are left alone rather than blown up:
+            Normalise::PerAxis => Vec3::select(half.cmpgt(Vec3::ZERO), 1.0 / half, Vec3::ONE),
+        };
+
+        for p in self.positions.iter_mut() {
+            *p = ((p.xyz() - centre) * scale).extend(1.0);
+        }
+        // Inverse transpose of the scale, only matters when it isn't uniform:
+        for n in self.normals.iter_mut() {
+            *n = (n.xyz() / scale).normalize_or_zero().extend(0.0);
+        }
+        // Tangents lie along the surface so they scale like positions:
+        for t in self.tangents.iter_mut() {
+            *t = (t.xyz() * scale).normalize_or_zero().extend(t.w);
         }
     }
 
@@ -412,6 +582,60 @@ impl Mesh {
         acc
     }
 
+    // Tangents the way MikkTSpace builds them: each triangle's uv derivatives projected onto the
+    // vertex normal's plane and weighted by the angle of its corner. The bitangent is the
+    // direction a normal map's green channel points, up the image, so against +v.
+    fn compute_vertex_tangents(
+        positions: &[Vec4],
+        normals: &[Vec4],
+        uvs: &[Vec2],
+        faces: &[UVec4],
+    ) -> Vec<Vec4> {
+        if uvs.len() < positions.len() || normals.len() < positions.len() {
+            return vec![Vec4::ZERO; positions.len()];
+        }
+
+        let mut tangents = vec![Vec3::ZERO; positions.len()];
+        let mut bitangents = vec![Vec3::ZERO; positions.len()];
+        for face in faces {
+            let corners = [face.x, face.y, face.z].map(|i| i as usize);
+            let [p0, p1, p2] = corners.map(|i| positions[i].xyz());
+            let [t0, t1, t2] = corners.map(|i| uvs[i]);
+
+            let (e1, e2) = (p1 - p0, p2 - p0);
+            let (d1, d2) = (t1 - t0, t2 - t0);
+            let det = d1.x * d2.y - d2.x * d1.y;
+            if det.abs() <= f32::EPSILON {
+                continue;
+            }
+            let tangent = (e1 * d2.y - e2 * d1.y) / det;
+            let bitangent = (e1 * d2.x - e2 * d1.x) / det;
+
+            for (c, &i) in corners.iter().enumerate() {
+                let p = positions[i].xyz();
+                let a = (positions[corners[(c + 1) % 3]].xyz() - p).normalize_or_zero();
+                let b = (positions[corners[(c + 2) % 3]].xyz() - p).normalize_or_zero();
+                let angle = a.dot(b).clamp(-1.0, 1.0).acos();
+
+                let n = normals[i].xyz();
+                tangents[i] += (tangent - n * n.dot(tangent)).normalize_or_zero() * angle;
+                bitangents[i] += (bitangent - n * n.dot(bitangent)).normalize_or_zero() * angle;
+            }
+        }
+
+        tangents
+            .into_iter()
+            .zip(bitangents)
+            .zip(normals)
+            .map(|((t, b), n)| {
+                let n = n.xyz();
+                let t = (t - n * n.dot(t)).normalize_or_zero();
+                let w = if n.cross(t).dot(b) < 0.0 { -1.0 } else { 1.0 };
+                t.extend(w)
+            })
+            .collect()
+    }
+
     pub fn rect() -> Self {
         let positions = vec![
             Vec4::new(-0.5, -0.5, 0.0, 1.0),
@@ -429,10 +653,20 @@ impl Mesh {
 
         let faces = vec![UVec4::new(0, 1, 2, 0), UVec4::new(0, 2, 3, 0)];
 
+        let uvs = vec![
+            Vec2::new(0.0, 1.0),
+            Vec2::new(1.0, 1.0),
+            Vec2::new(1.0, 0.0),
+            Vec2::new(0.0, 0.0),
+        ];
+        let tangents = Self::compute_vertex_tangents(&positions, &normals, &uvs, &faces);
+
         Self {
             positions,
             normals,
             faces,
+            uvs,
+            tangents,
         }
     }
 
@@ -528,10 +762,20 @@ impl Mesh {
         .map(UVec4::from_array)
         .collect_vec();
 
+        // Every face gets the whole image, the same way up as the rect:
+        let uvs = [[0.0, 1.0], [1.0, 1.0], [1.0, 0.0], [0.0, 0.0]]
+            .repeat(6)
+            .into_iter()
+            .map(Vec2::from_array)
+            .collect_vec();
+        let tangents = Self::compute_vertex_tangents(&positions, &normals, &uvs, &faces);
+
         Self {
             positions,
             normals,
             faces,
+            uvs,
+            tangents,
         }
     }
 }
@@ -0,0 +1,254 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use bevy_ecs::prelude::*;
+use crossbeam::channel::{Receiver, TryRecvError, bounded};
+use glam::{Vec3, Vec4};
+use itertools::Itertools;
+
+use crate::{
+    material::{Material, MaterialId, MaterialServer},
+    mesh::{Mesh, MeshDescriptor, MeshServer, Normalise},
+    transform::Transform,
+};
+
+// Single index so texture coordinates and normals line up with the positions, vertices on
+// seams get split.
+fn load_options() -> tobj::LoadOptions {
+    tobj::GPU_LOAD_OPTIONS
+}
+
+// Every model in the file as a mesh, normalised together so multi-part assets stay assembled.
+fn meshes_from_models(models: &[tobj::Model], normalise: Normalise) -> Vec<Mesh> {
+    let mut meshes = models
+        .iter()
+        .map(|m| Mesh::from_model(&m.mesh))
+        .collect_vec();
+
+    if let Some(bounds) = meshes.iter().map(Mesh::aabb).reduce(|a, b| a.union(&b)) {
+        for mesh in meshes.iter_mut() {
+            mesh.normalise(normalise, bounds);
+        }
+    }
+
+    meshes
+}
+
+// An obj file being parsed in the background, see obj_loading_system.
+pub struct ObjLoading {
+    path: PathBuf,
+    transform: Transform,
+    normalise: Normalise,
+    rx: Receiver<anyhow::Result<ObjFile>>,
+}
+
+struct ObjFile {
+    meshes: Vec<Mesh>,
+    // Index into materials of each mesh's material:
+    material_ids: Vec<Option<usize>>,
+    materials: Vec<tobj::Material>,
+}
+
+// Spawns every model in the obj as its own instance, all sharing the given transform, with
+// materials converted from the mtl files it references. The file is parsed in the background
+// and counts towards MeshServer::is_loading until it's in.
+pub fn load_obj(
+    path: &Path,
+    mesh_server: &mut MeshServer,
+    transform: Transform,
+    normalise: Normalise,
+) -> anyhow::Result<()> {
+    // Catch a bad path here rather than on another thread:
+    std::fs::metadata(path).with_context(|| format!("Failed to open {}", path.display()))?;
+
+    let (tx, rx) = bounded(1);
+    rayon::spawn({
+        let path = path.to_owned();
+        move || {
+            let file = tobj::load_obj(&path, &load_options())
+                .with_context(|| format!("Failed to load {}", path.display()))
+                .map(|(models, materials)| {
+                    // A broken or missing mtl shouldn't stop the geometry from loading:
+                    let materials = materials.unwrap_or_else(|e| {
+                        tracing::warn!("Failed to load materials for {}: {}", path.display(), e);
+                        Vec::new()
+                    });
+
+                    ObjFile {
+                        meshes: meshes_from_models(&models, normalise),
+                        material_ids: models.iter().map(|m| m.mesh.material_id).collect(),
+                        materials,
+                    }
+                });
+            tx.send(file).expect("Expected to send obj file");
+        }
+    });
+
+    mesh_server.load_obj(ObjLoading {
+        path: path.to_owned(),
+        transform,
+        normalise,
+        rx,
+    });
+    Ok(())
+}
+
+// Adds the meshes and materials of obj files that have finished parsing and spawns their
+// instances.
+pub fn obj_loading_system(
+    mut commands: Commands,
+    mut mesh_server: ResMut<MeshServer>,
+    mut material_server: ResMut<MaterialServer>,
+) {
+    // Nothing has changed until the meshes themselves are loaded:
+    let mesh_server = mesh_server.bypass_change_detection();
+    for loading in mesh_server.take_obj_loading() {
+        match loading.rx.try_recv() {
+            Ok(Ok(file)) => spawn_obj(
+                &loading,
+                file,
+                &mut commands,
+                mesh_server,
+                &mut material_server,
+            ),
+            // Dropped, so a bad file doesn't keep is_loading set forever:
+            Ok(Err(e)) => tracing::warn!("{:#}", e),
+            Err(TryRecvError::Disconnected) => {
+                tracing::warn!("Gave up loading {}", loading.path.display())
+            }
+            Err(TryRecvError::Empty) => mesh_server.load_obj(loading),
+        }
+    }
+}
+
+fn spawn_obj(
+    loading: &ObjLoading,
+    file: ObjFile,
+    commands: &mut Commands,
+    mesh_server: &mut MeshServer,
+    material_server: &mut MaterialServer,
+) {
+    let label = loading.path.to_string_lossy().into_owned();
+    let dir = loading.path.parent().unwrap_or(Path::new(""));
+    let material_ids = file
+        .materials
+        .iter()
+        .map(|m| {
+            let material = material_from_mtl(m, dir, material_server);
+            material_server.add_material_labelled(material, format!("{}#{}", label, m.name))
+        })
+        .collect_vec();
+
+    for (i, (mesh, material)) in file.meshes.into_iter().zip(file.material_ids).enumerate() {
+        let mesh_id = mesh_server.add_mesh(
+            MeshDescriptor::TOBJ {
+                path: label.clone(),
+                model: i,
+                normalise: loading.normalise,
+            },
+            mesh,
+        );
+
+        let material_id: MaterialId = match material.and_then(|m| material_ids.get(m)) {
+            Some(id) => *id,
+            None => material_server.add_material_labelled(
+                Material {
+                    colour: Vec4::new(0.8, 0.8, 0.8, 1.0),
+                    roughness: 1.0,
+                    ..Default::default()
+                },
+                format!("{}#default", label),
+            ),
+        };
+
+        commands.spawn((loading.transform, mesh_id, material_id));
+    }
+}
+
+// Loads a single model on its own, for when a mesh is referenced without the rest of its file.
+pub fn load_model(path: &str, model: usize, normalise: Normalise) -> anyhow::Result<Mesh> {
+    let (models, _) = tobj::load_obj(path, &load_options())?;
+    meshes_from_models(&models, normalise)
+        .into_iter()
+        .nth(model)
+        .with_context(|| format!("No model {model} in {path}"))
+}
+
+// Based on http://paulbourke.net/dataformats/mtl/ along with the common PBR extensions
+// (Pr, Pm, Ke), falling back to guesses from the phong terms where they're missing.
+fn material_from_mtl(
+    material: &tobj::Material,
+    dir: &Path,
+    material_server: &mut MaterialServer,
+) -> Material {
+    let param = |name: &str| {
+        material.unknown_param.get(name).map(|p| {
+            p.split_whitespace()
+                .filter_map(|f| f.parse::<f32>().ok())
+                .collect_vec()
+        })
+    };
+
+    let diffuse = Vec3::from(material.diffuse.unwrap_or([0.8; 3]));
+    let specular = Vec3::from(material.specular.unwrap_or([0.0; 3]));
+    let emissive = param("Ke")
+        .filter(|ke| ke.len() >= 3)
+        .map(|ke| Vec3::from_slice(&ke))
+        .unwrap_or(Vec3::ZERO);
+
+    // illum 3 is "reflection on", the specular colour is then the reflectance of a mirror:
+    let metallic = param("Pm")
+        .and_then(|pm| pm.first().copied())
+        .unwrap_or_else(|| match material.illumination_model {
+            Some(3) => specular.max_element(),
+            _ => 0.0,
+        });
+
+    // d/Tr dissolve is coverage for cut outs rather than refraction. It's kept in the colour's
+    // alpha like glTF's base colour alpha, but nothing renders coverage yet:
+    let dissolve = material
+        .dissolve
+        .or_else(|| param("Tr").and_then(|tr| tr.first().map(|tr| 1.0 - tr)))
+        .unwrap_or(1.0)
+        .clamp(0.0, 1.0);
+    if dissolve < 1.0 {
+        tracing::warn!(
+            "Material {} has dissolve {}, it will be rendered opaque",
+            material.name,
+            dissolve
+        );
+    }
+
+    // The usual blinn-phong exponent to beckmann roughness fit:
+    let roughness = param("Pr")
+        .and_then(|pr| pr.first().copied())
+        .or(material.shininess.map(|ns| (2.0 / (ns + 2.0)).sqrt()))
+        .unwrap_or(1.0);
+
+    // Texture paths are relative to the mtl, which we assume sits next to the obj. There's no
+    // metallic/roughness map in plain mtl so that slot is left empty:
+    let mut texture = |t: &Option<String>| {
+        t.as_ref()
+            .map(|t| material_server.add_texture(dir.join(t)))
+            .unwrap_or(0)
+    };
+
+    Material {
+        colour_texture: texture(&material.diffuse_texture),
+        emissive_texture: texture(&material.unknown_param.get("map_Ke").cloned()),
+        metallic_roughness_texture: 0,
+        // tobj only knows the bump keywords, the PBR extension calls it norm:
+        normal_texture: texture(
+            &material
+                .normal_texture
+                .clone()
+                .or_else(|| material.unknown_param.get("norm").cloned()),
+        ),
+        colour: diffuse.extend(dissolve),
+        emissive: emissive.extend(0.0),
+        metallic: metallic.clamp(0.0, 1.0),
+        roughness: roughness.clamp(0.0, 1.0),
+        ior: material.optical_density.unwrap_or(1.5),
+        transmission: 0.0,
+    }
+}
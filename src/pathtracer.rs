@@ -1,9 +1,18 @@
+use std::{path::Path, str::FromStr};
+
+use anyhow::Context;
 use bevy_ecs::prelude::*;
+use serde::{Deserialize, Serialize};
 use wgpu::util::DeviceExt;
 
 use crate::{
-    app::BevyApp, camera::Camera, pathtracer_state::PathtracerState,
-    render_resources::RenderDevice, schedule,
+    app::BevyApp,
+    camera::Camera,
+    cli::Args,
+    pathtracer_state::{PathtracerState, TileOrder},
+    render_resources::{RenderDevice, read_buffer},
+    scene_file::SceneFile,
+    schedule,
 };
 
 #[derive(Component)]
@@ -11,6 +20,25 @@ pub struct Pathtracer {
     pub is_primary: bool,
     pub dims: (u32, u32),
     pub threads: u32,
+    pub samples_per_pixel: u32,
+    pub max_bounces: u32,
+    pub seed: Option<u64>,
+    // Relative error under which a pixel stops being sampled, None samples every pixel evenly:
+    pub adaptive_threshold: Option<f32>,
+    pub min_samples: u32,
+    pub tile_size: u32,
+    pub tile_order: TileOrder,
+    // Only this part of the frame is sampled, the rest stays black:
+    pub crop: Option<Crop>,
+}
+
+// A window of the frame in pixels, from the top left of the saved image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
 }
 
 #[derive(Component)]
@@ -18,6 +46,8 @@ pub struct PathtracerOutput {
     pub source_bind_group_layout: wgpu::BindGroupLayout,
     pub source_bind_group: wgpu::BindGroup,
     pub source_buffer: wgpu::Buffer,
+    // Mean radiance of each pixel before tonemapping as rgba32f, with the sample count in alpha:
+    pub radiance_buffer: wgpu::Buffer,
     pub out_texture: wgpu::Texture,
     pub out_sampler: wgpu::Sampler,
 }
@@ -29,15 +59,146 @@ pub fn initialize(app: &mut BevyApp) {
         .add_systems(schedule::Update, pathtracer_output_sync_system);
 }
 
-fn setup_pathtracer(mut commands: Commands, device: Res<RenderDevice>) {
-    commands.spawn((
-        Pathtracer {
+fn setup_pathtracer(
+    mut commands: Commands,
+    device: Res<RenderDevice>,
+    args: Res<Args>,
+    scene: Option<Res<SceneFile>>,
+) {
+    let mut camera = Camera::new(&device.0, Some("Camera"));
+    let scene_camera = scene.as_ref().and_then(|s| s.camera.as_ref());
+    if let Some(c) = scene_camera {
+        camera.set_view(c.position, c.forward, c.up, c.focal_length);
+    }
+    camera.set_aspect(args.width() as f32 / args.height() as f32);
+    camera.set_lens(&args.lens(scene_camera.map(|c| c.lens).unwrap_or_default()));
+    camera.set_projection(&args.projection(scene_camera.map(|c| c.projection).unwrap_or_default()));
+    camera.autofocus = args.autofocus;
+
+    commands.spawn((Pathtracer::primary(&args), camera));
+}
+
+impl Pathtracer {
+    // The pathtracer rendering to the window or output file, as set up by the command line.
+    pub fn primary(args: &Args) -> Self {
+        Self {
             is_primary: true,
-            dims: (512, 512),
-            threads: 512 * 512,
-        },
-        Camera::new(&device.0, Some("Camera")),
-    ));
+            dims: (args.width(), args.height()),
+            threads: args.threads(),
+            samples_per_pixel: args.samples(),
+            max_bounces: args.max_bounces(),
+            seed: args.seed,
+            adaptive_threshold: args.adaptive_threshold,
+            min_samples: args.min_samples(),
+            tile_size: args.tile_size(),
+            tile_order: args.tile_order(),
+            crop: args.crop,
+        }
+    }
+
+    // The part of the frame being sampled, all of it without a crop.
+    pub fn region(&self) -> Crop {
+        self.crop.unwrap_or(Crop {
+            x: 0,
+            y: 0,
+            width: self.dims.0,
+            height: self.dims.1,
+        })
+    }
+}
+
+impl Crop {
+    pub fn area(&self) -> u32 {
+        self.width * self.height
+    }
+
+    pub fn fits(&self, dims: (u32, u32)) -> bool {
+        self.area() > 0
+            && self.x.saturating_add(self.width) <= dims.0
+            && self.y.saturating_add(self.height) <= dims.1
+    }
+
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        (self.x..self.x + self.width).contains(&x) && (self.y..self.y + self.height).contains(&y)
+    }
+
+    // The same window of the pathtracer's output, which is mirrored from the saved image.
+    pub fn mirrored(&self, frame_width: u32) -> Crop {
+        Crop {
+            x: frame_width - self.x - self.width,
+            ..*self
+        }
+    }
+}
+
+// Parsed from x,y,width,height on the command line.
+impl FromStr for Crop {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<u32> = s
+            .split(',')
+            .map(|p| p.trim().parse())
+            .collect::<Result<_, _>>()
+            .context("Expected a crop of x,y,width,height in pixels")?;
+        let [x, y, width, height] = parts[..] else {
+            anyhow::bail!("Expected a crop of x,y,width,height in pixels");
+        };
+        Ok(Crop {
+            x,
+            y,
+            width,
+            height,
+        })
+    }
+}
+
+// Writes a render to path. With a crop it's either cut down to the crop, or the crop is pasted
+// over the full frame in merge_into so only that window is replaced. `load` converts the frame
+// being merged into to the same pixel type as the render.
+pub fn save_render<P>(
+    image: image::ImageBuffer<P, Vec<P::Subpixel>>,
+    path: &Path,
+    crop: Option<Crop>,
+    cropped: bool,
+    merge_into: Option<&Path>,
+    load: impl FnOnce(image::DynamicImage) -> image::ImageBuffer<P, Vec<P::Subpixel>>,
+) -> anyhow::Result<()>
+where
+    P: image::PixelWithColorType + 'static,
+    [P::Subpixel]: image::EncodableLayout,
+{
+    let crop = crop.unwrap_or(Crop {
+        x: 0,
+        y: 0,
+        width: image.width(),
+        height: image.height(),
+    });
+    let window = image::imageops::crop_imm(&image, crop.x, crop.y, crop.width, crop.height);
+
+    if let Some(merge_into) = merge_into {
+        let base = image::open(merge_into)
+            .with_context(|| format!("Failed to open {} to merge into", merge_into.display()))?;
+        let mut base = load(base);
+        if base.dimensions() != image.dimensions() {
+            anyhow::bail!(
+                "Can't merge a {}x{} render into {}, which is {}x{}",
+                image.width(),
+                image.height(),
+                merge_into.display(),
+                base.width(),
+                base.height()
+            );
+        }
+        image::imageops::replace(&mut base, &*window, crop.x as i64, crop.y as i64);
+        base.save(path)?;
+    } else if cropped {
+        window.to_image().save(path)?;
+    } else {
+        image.save(path)?;
+    }
+
+    Ok(())
 }
 
 pub fn pathtracer_output_sync_system(
@@ -49,18 +210,31 @@ pub fn pathtracer_output_sync_system(
         commands
             .entity(id)
             .insert(PathtracerOutput::new(&device.0, pt.dims))
-            .insert(PathtracerState::new(&device.0, pt.dims, pt.threads));
+            .insert(PathtracerState::new(&device.0, pt));
     }
 }
 
+// Bytes between rows of the packed output, padded so they can all be copied to the texture at once.
+pub fn output_row_pitch(width: u32) -> u32 {
+    (4 * width).next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT)
+}
+
 impl PathtracerOutput {
     fn new(device: &wgpu::Device, dims: (u32, u32)) -> Self {
         let source_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("LogicPhase Output"),
-            contents: bytemuck::cast_slice(&(0..(dims.0 * dims.1)).collect::<Vec<_>>()),
+            // Black until sampled, which is forever outside of a crop:
+            contents: &vec![0u8; (output_row_pitch(dims.0) * dims.1) as usize],
             usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::STORAGE,
         });
 
+        let radiance_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Radiance Output"),
+            size: (dims.0 * dims.1) as u64 * 16,
+            usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+
         let size = wgpu::Extent3d {
             width: dims.0,
             height: dims.1,
@@ -91,43 +265,118 @@ impl PathtracerOutput {
         let source_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 label: Some("Output Bind Group Layout"),
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::COMPUTE,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: false },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: false },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::COMPUTE,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Storage { read_only: false },
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
                     },
-                    count: None,
-                }],
+                ],
             });
 
         let source_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: Some("Output Bind Group"),
             layout: &source_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: source_buffer.as_entire_binding(),
-            }],
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: source_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: radiance_buffer.as_entire_binding(),
+                },
+            ],
         });
 
         Self {
             source_bind_group_layout,
             source_bind_group,
             source_buffer,
+            radiance_buffer,
             out_texture,
             out_sampler,
         }
     }
 
+    // Reads the packed output back as tightly packed RGBA8 rows, in the same orientation as it is
+    // shown on screen.
+    pub fn read_image(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> anyhow::Result<image::RgbaImage> {
+        let size = self.out_texture.size();
+        let bytes = read_buffer(
+            device,
+            queue,
+            &self.source_buffer,
+            self.source_buffer.size(),
+        )?;
+
+        let pitch = output_row_pitch(size.width) as usize;
+        let rows = bytes
+            .chunks_exact(pitch)
+            .flat_map(|row| &row[..4 * size.width as usize])
+            .copied()
+            .collect();
+        let mut image = image::RgbaImage::from_raw(size.width, size.height, rows)
+            .ok_or_else(|| anyhow::anyhow!("Output buffer does not match output dimensions"))?;
+
+        // Pixels are packed without alpha, and the display pass mirrors the buffer horizontally.
+        image.pixels_mut().for_each(|p| p[3] = 255);
+        image::imageops::flip_horizontal_in_place(&mut image);
+
+        Ok(image)
+    }
+
+    // Reads back the running mean radiance, untonemapped, oriented the same as read_image.
+    pub fn read_radiance(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> anyhow::Result<image::Rgb32FImage> {
+        let size = self.out_texture.size();
+        let bytes = read_buffer(
+            device,
+            queue,
+            &self.radiance_buffer,
+            self.radiance_buffer.size(),
+        )?;
+
+        // The staging copy has no alignment guarantees, so read each pixel unaligned:
+        let mut image = image::Rgb32FImage::from_fn(size.width, size.height, |x, y| {
+            let i = (x + y * size.width) as usize * 16;
+            let [r, g, b, _]: [f32; 4] = bytemuck::pod_read_unaligned(&bytes[i..i + 16]);
+            image::Rgb([r, g, b])
+        });
+        image::imageops::flip_horizontal_in_place(&mut image);
+
+        Ok(image)
+    }
+
     pub fn copy_to_texture(&self, encoder: &mut wgpu::CommandEncoder) {
         encoder.copy_buffer_to_texture(
             wgpu::TexelCopyBufferInfoBase {
                 buffer: &self.source_buffer,
                 layout: wgpu::TexelCopyBufferLayout {
                     offset: 0,
-                    bytes_per_row: Some(4 * self.out_texture.size().width),
+                    bytes_per_row: Some(output_row_pitch(self.out_texture.size().width)),
                     rows_per_image: Some(self.out_texture.size().height),
                 },
             },
@@ -145,3 +394,76 @@ impl PathtracerOutput {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn crop(x: u32, y: u32, width: u32, height: u32) -> Crop {
+        Crop {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    #[test]
+    fn crop_from_str() {
+        assert_eq!("10,20,30,40".parse::<Crop>().unwrap(), crop(10, 20, 30, 40));
+        assert_eq!(" 1, 2 ,3,4 ".parse::<Crop>().unwrap(), crop(1, 2, 3, 4));
+        for bad in ["", "1,2,3", "1,2,3,4,5", "1,2,3,x", "-1,0,1,1", "1;2;3;4"] {
+            assert!(bad.parse::<Crop>().is_err(), "{bad:?} parsed");
+        }
+    }
+
+    #[test]
+    fn crop_fits() {
+        let dims = (1920, 1080);
+        assert!(crop(0, 0, 1920, 1080).fits(dims));
+        assert!(crop(1919, 1079, 1, 1).fits(dims));
+        assert!(!crop(1919, 0, 2, 1).fits(dims));
+        assert!(!crop(0, 1080, 1, 1).fits(dims));
+        assert!(!crop(10, 10, 0, 5).fits(dims));
+        // Would wrap back inside the frame without saturating:
+        assert!(!crop(u32::MAX, 0, 2, 1).fits(dims));
+        assert!(!crop(0, 1, 1, u32::MAX).fits(dims));
+
+        let c = crop(100, 20, 300, 40);
+        assert_eq!(c.mirrored(1920), crop(1520, 20, 300, 40));
+        assert_eq!(c.mirrored(1920).mirrored(1920), c);
+    }
+
+    #[test]
+    fn save_render_merges_crop() {
+        let dir = std::env::temp_dir().join(format!("raytracer-crop-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (base, out) = (dir.join("base.png"), dir.join("out.png"));
+        let white = image::Rgba([255u8; 4]);
+        let red = image::Rgba([255, 0, 0, 255]);
+        image::RgbaImage::from_pixel(6, 4, white)
+            .save(&base)
+            .unwrap();
+
+        let render = image::RgbaImage::from_pixel(6, 4, red);
+        let c = crop(1, 2, 3, 2);
+        let load = |i: image::DynamicImage| i.to_rgba8();
+
+        save_render(render.clone(), &out, Some(c), false, Some(&base), load).unwrap();
+        let merged = image::open(&out).unwrap().to_rgba8();
+        for (x, y, p) in merged.enumerate_pixels() {
+            assert_eq!(*p, if c.contains(x, y) { red } else { white }, "{x},{y}");
+        }
+
+        save_render(render.clone(), &out, Some(c), true, None, load).unwrap();
+        let cropped = image::open(&out).unwrap().to_rgba8();
+        assert_eq!(cropped.dimensions(), (3, 2));
+        assert!(cropped.pixels().all(|p| *p == red));
+
+        // A base of another size can't line up with the crop:
+        let small = image::RgbaImage::from_pixel(5, 4, red);
+        assert!(save_render(small, &out, Some(c), false, Some(&base), load).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -5,20 +5,29 @@ use wgpu::{CommandBuffer, include_spirv, util::DeviceExt};
 use crate::{
     app::BevyApp,
     binder::{SceneBindings, binder_system},
-    camera::Camera,
+    camera::{Camera, camera_buffer_system},
+    mesh::MeshServer,
     pathtracer::{Pathtracer, PathtracerOutput, pathtracer_output_sync_system},
     pathtracer_state::PathtracerState,
     render::render_system,
     render_resources::{RenderDevice, RenderQueue, RenderSurface},
     schedule,
+    texture::TextureServer,
 };
 
+// Frames between checks for converged pixels with adaptive sampling.
+const CONVERGE_INTERVAL: u32 = 8;
+
 #[derive(Component)]
 pub struct PathtracerPhase {
     sample_main_pipeline: wgpu::ComputePipeline,
     sample_cleanup_pipeline: wgpu::ComputePipeline,
+    sample_converge_pipeline: wgpu::ComputePipeline,
+    sample_resolve_pipeline: wgpu::ComputePipeline,
     ray_extend_pipeline: wgpu::ComputePipeline,
     shade_pipeline: wgpu::ComputePipeline,
+    ray_connect_pipeline: wgpu::ComputePipeline,
+    focus_pipeline: wgpu::ComputePipeline,
 }
 
 pub fn initialize(app: &mut BevyApp) {
@@ -27,11 +36,16 @@ pub fn initialize(app: &mut BevyApp) {
         (
             pathtracer_phase_execute
                 .before(render_system)
-                .after(binder_system),
+                .after(binder_system)
+                .after(camera_buffer_system),
             pathtracer_phase_sync
                 .before(pathtracer_phase_execute)
                 .after(pathtracer_output_sync_system)
                 .after(binder_system),
+            autofocus_system
+                .before(pathtracer_phase_execute)
+                .after(pathtracer_phase_sync)
+                .after(camera_buffer_system),
         ),
     );
 }
@@ -54,7 +68,7 @@ fn pathtracer_phase_sync(
 ) {
     // Update all the path tracer states to be reset:
     for (e, pt, pto, pts, ptp, camera) in pathtracer_query {
-        let new_pts = PathtracerState::new(&device.0, pt.dims, pt.threads);
+        let new_pts = PathtracerState::new(&device.0, pt);
         let new_ptp = PathtracerPhase::new(&device.0, &pto, &scene_bindings, &new_pts, camera);
 
         if let Some(mut pts) = pts {
@@ -71,23 +85,42 @@ fn pathtracer_phase_sync(
     }
 }
 
-fn pathtracer_phase_execute(
+pub fn pathtracer_phase_execute(
     device: Res<RenderDevice>,
     queue: Res<RenderQueue>,
-    query: Query<(
+    mut query: Query<(
         &Pathtracer,
         &PathtracerOutput,
-        &PathtracerState,
+        &mut PathtracerState,
         &PathtracerPhase,
-        &Camera,
+        Ref<Camera>,
     )>,
     scene_bindings: Res<SceneBindings>,
+    mesh_server: Res<MeshServer>,
+    texture_server: Res<TextureServer>,
 ) {
+    // Anything that changes the image starts accumulating it over. Checked before bailing out
+    // below so changes made while loading aren't missed:
+    for (_, _, mut pts, _, camera) in query.iter_mut() {
+        if camera.is_changed() || pts.scene_epoch != scene_bindings.epoch {
+            pts.scene_epoch = scene_bindings.epoch;
+            pts.reset_accumulation();
+        }
+    }
+
     if scene_bindings.bind_group.is_none() {
         return;
     }
 
-    for (pt, pto, pts, ptp, camera) in query {
+    // Don't accumulate samples of a half loaded scene.
+    if mesh_server.is_loading() || texture_server.is_loading() {
+        return;
+    }
+
+    for (pt, pto, mut pts, ptp, camera) in query.iter_mut() {
+        pts.write_accumulation(&queue.0);
+        pts.frame = pts.frame.wrapping_add(1);
+
         let mut encoder = device
             .0
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
@@ -106,15 +139,28 @@ fn pathtracer_phase_execute(
         compute_pass.set_bind_group(3, &pto.source_bind_group, &[]);
         compute_pass.dispatch_workgroups(4096.min((pt.dims.0 * pt.dims.1).div_ceil(64)), 1, 1);
 
+        // Every so often, stop spending samples on pixels that have converged:
+        if pt.adaptive_threshold.is_some() && pts.frame % CONVERGE_INTERVAL == 0 {
+            compute_pass.set_pipeline(&ptp.sample_converge_pipeline);
+            compute_pass.dispatch_workgroups(4096.min((pt.dims.0 * pt.dims.1).div_ceil(64)), 1, 1);
+        }
+
         compute_pass.set_pipeline(&ptp.sample_main_pipeline);
         compute_pass.dispatch_workgroups(pt.threads.div_ceil(64), 1, 1);
 
+        // The sums and counts are only settled once every sample has been accumulated:
+        compute_pass.set_pipeline(&ptp.sample_resolve_pipeline);
+        compute_pass.dispatch_workgroups(4096.min((pt.dims.0 * pt.dims.1).div_ceil(64)), 1, 1);
+
         compute_pass.set_pipeline(&ptp.ray_extend_pipeline);
         compute_pass.dispatch_workgroups(pt.threads.div_ceil(64), 1, 1);
 
         compute_pass.set_pipeline(&ptp.shade_pipeline);
         compute_pass.dispatch_workgroups(pt.threads.div_ceil(64), 1, 1);
 
+        compute_pass.set_pipeline(&ptp.ray_connect_pipeline);
+        compute_pass.dispatch_workgroups(pt.threads.div_ceil(64), 1, 1);
+
         drop(compute_pass);
 
         let command = encoder.finish();
@@ -123,6 +169,73 @@ fn pathtracer_phase_execute(
     }
 }
 
+// Focuses cameras that asked for it on whatever is under the centre of the screen. Runs
+// before the frame is traced so the samples it spawns are already using the new focus.
+fn autofocus_system(
+    device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+    query: Query<(
+        &PathtracerOutput,
+        &PathtracerState,
+        &PathtracerPhase,
+        &mut Camera,
+    )>,
+    scene_bindings: Res<SceneBindings>,
+    mesh_server: Res<MeshServer>,
+    texture_server: Res<TextureServer>,
+) {
+    // Wait for the whole scene so it doesn't focus on the background behind a missing mesh:
+    let Some(scene_bind_group) = scene_bindings.bind_group.as_ref() else {
+        return;
+    };
+    if mesh_server.is_loading() || texture_server.is_loading() {
+        return;
+    }
+
+    for (pto, pts, ptp, mut camera) in query {
+        if !camera.autofocus {
+            continue;
+        }
+
+        let mut encoder = device
+            .0
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Autofocus Encoder"),
+            });
+
+        let mut compute_pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Autofocus Pass"),
+            timestamp_writes: None,
+        });
+        compute_pass.set_pipeline(&ptp.focus_pipeline);
+        compute_pass.set_bind_group(0, scene_bind_group, &[]);
+        compute_pass.set_bind_group(1, &pts.bind_group, &[]);
+        compute_pass.set_bind_group(2, &camera.bind_group, &[]);
+        compute_pass.set_bind_group(3, &pto.source_bind_group, &[]);
+        compute_pass.dispatch_workgroups(1, 1, 1);
+        drop(compute_pass);
+
+        queue.0.submit([encoder.finish()]);
+
+        // Clearing the flag alone shouldn't restart the accumulation, only a new focus does:
+        camera.bypass_change_detection().autofocus = false;
+        let d = match pts.focus_distance(&device.0, &queue.0) {
+            Ok(d) => d,
+            Err(e) => {
+                tracing::warn!("Failed to read back the focus distance: {}", e);
+                continue;
+            }
+        };
+        if d > 0.0 {
+            tracing::info!("Autofocused at {:.3}", d);
+            camera.set_focus_distance(d);
+            camera.update(&queue.0);
+        } else {
+            tracing::warn!("Nothing in the centre of the image to focus on");
+        }
+    }
+}
+
 // pub fn render_system(
 //     device: Res<RenderDevice>,
 //     queue: Res<RenderQueue>,
@@ -209,6 +322,12 @@ impl PathtracerPhase {
         let shade_shader =
             device.create_shader_module(include_spirv!(concat!(env!("OUT_DIR"), "/shade.spv")));
 
+        let ray_connect_shader = device
+            .create_shader_module(include_spirv!(concat!(env!("OUT_DIR"), "/ray_connect.spv")));
+
+        let focus_shader =
+            device.create_shader_module(include_spirv!(concat!(env!("OUT_DIR"), "/focus.spv")));
+
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("Pathtracer Pipeline Layout"),
             bind_group_layouts: &[
@@ -246,6 +365,32 @@ impl PathtracerPhase {
                 cache: None,
             });
 
+        let sample_converge_pipeline =
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("Pathtracer Sample Converge Pipeline"),
+                layout: Some(&pipeline_layout),
+                module: &sample_shader,
+                entry_point: Some("sampleConverge"),
+                compilation_options: wgpu::PipelineCompilationOptions {
+                    constants: &[],
+                    zero_initialize_workgroup_memory: false,
+                },
+                cache: None,
+            });
+
+        let sample_resolve_pipeline =
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("Pathtracer Sample Resolve Pipeline"),
+                layout: Some(&pipeline_layout),
+                module: &sample_shader,
+                entry_point: Some("sampleResolve"),
+                compilation_options: wgpu::PipelineCompilationOptions {
+                    constants: &[],
+                    zero_initialize_workgroup_memory: false,
+                },
+                cache: None,
+            });
+
         let ray_extend_pipeline =
             device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
                 label: Some("Pathtracer Ray Extend Pipeline"),
@@ -271,11 +416,40 @@ impl PathtracerPhase {
             cache: None,
         });
 
+        let ray_connect_pipeline =
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some("Pathtracer Ray Connect Pipeline"),
+                layout: Some(&pipeline_layout),
+                module: &ray_connect_shader,
+                entry_point: Some("main"),
+                compilation_options: wgpu::PipelineCompilationOptions {
+                    constants: &[],
+                    zero_initialize_workgroup_memory: false,
+                },
+                cache: None,
+            });
+
+        let focus_pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Pathtracer Focus Pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &focus_shader,
+            entry_point: Some("main"),
+            compilation_options: wgpu::PipelineCompilationOptions {
+                constants: &[],
+                zero_initialize_workgroup_memory: false,
+            },
+            cache: None,
+        });
+
         PathtracerPhase {
             sample_main_pipeline,
             sample_cleanup_pipeline,
+            sample_converge_pipeline,
+            sample_resolve_pipeline,
             ray_extend_pipeline,
             shade_pipeline,
+            ray_connect_pipeline,
+            focus_pipeline,
         }
     }
 }
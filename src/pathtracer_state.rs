@@ -1,11 +1,17 @@
 use bevy_ecs::component::Component;
 use bytemuck::Zeroable;
+use clap::ValueEnum;
 use glam::{UVec4, Vec4};
 use itertools::Itertools;
-use rand::{Rng, seq::SliceRandom};
+use rand::{Rng, SeedableRng, rngs::StdRng, seq::SliceRandom};
+use serde::{Deserialize, Serialize};
 use wgpu::util::DeviceExt;
 
-use crate::queue;
+use crate::{
+    pathtracer::{Crop, Pathtracer},
+    queue,
+    render_resources::read_buffer,
+};
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Zeroable, bytemuck::Pod)]
@@ -41,7 +47,8 @@ pub struct Sample {
     pub _pad1: u32, // pad to 16 byte boundary
     pub bounces: u32,
     pub sample_id: u32,
-    pub _pad2: [u32; 2],
+    pub pdf: f32,
+    pub epoch: u32,
 }
 
 #[repr(C)]
@@ -53,12 +60,9 @@ pub struct RandomState {
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Zeroable, bytemuck::Pod)]
 pub struct ShadowData {
-    pub dir: [f32; 3],
-    pub _pad1: u32,
+    // Light sampled at the last shade, added to the sample if its connect ray is unoccluded:
     pub rad: [f32; 3],
-    pub _pad2: u32,
-    pub prob: f32,
-    pub _pad3: [u32; 3],
+    pub _pad: u32,
 }
 
 #[repr(C)]
@@ -70,6 +74,44 @@ pub struct SampleSource {
     pub flags: u32,
 }
 
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Zeroable, bytemuck::Pod)]
+pub struct PathtracerSettings {
+    pub dims: [u32; 2],
+    pub max_bounces: u32,
+    // 0 samples every pixel evenly:
+    pub adaptive_threshold: f32,
+    pub min_samples: u32,
+    pub _pad: [u32; 3],
+}
+
+// Which accumulation epoch new samples belong to. Starting a new one clears what's been
+// accumulated, and paths from the old one are thrown away as they finish.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Zeroable, bytemuck::Pod, Default)]
+pub struct Accumulation {
+    pub epoch: u32,
+    // Set for the first dispatch of an epoch, see sampleCleanup:
+    pub reset: u32,
+    pub _pad: [u32; 2],
+}
+
+// The order tiles are handed out in by spawnSample, each sweep over the image goes through
+// them in this order.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TileOrder {
+    /// Tiles in a random order, with their pixels shuffled too
+    #[default]
+    Shuffled,
+    /// Left to right, top to bottom
+    Scanline,
+    /// Along a Hilbert curve, so consecutive tiles are always neighbours
+    Hilbert,
+    /// Outwards from the centre of the image
+    Spiral,
+}
+
 #[derive(Component)]
 pub struct PathtracerState {
     // Path tracer intermediate state:
@@ -82,6 +124,14 @@ pub struct PathtracerState {
     pub sampling_data_buffer: wgpu::Buffer,
     pub sampling_mean_buffer: wgpu::Buffer,
     pub sampling_std_buffer: wgpu::Buffer,
+    pub accumulation: Accumulation,
+    pub accumulation_buffer: wgpu::Buffer,
+    // Written by the autofocus pass, see focus.slang:
+    pub focus_buffer: wgpu::Buffer,
+    // SceneBindings::epoch the accumulation was last reset for:
+    pub scene_epoch: u32,
+    // Frames dispatched, for the passes that only run every so often:
+    pub frame: u32,
 
     // Queues:
     pub new_ray_queue: queue::Queue,
@@ -93,9 +143,101 @@ pub struct PathtracerState {
     pub bind_group: wgpu::BindGroup,
 }
 
+// Every pixel of the region, tile by tile. Tiles along the right and bottom edges are cut short
+// where the region doesn't divide into whole tiles.
+fn sample_sources(
+    dims: (u32, u32),
+    region: Crop,
+    tile_size: u32,
+    order: TileOrder,
+    rng: &mut StdRng,
+) -> Vec<SampleSource> {
+    let tile_size = tile_size.max(1);
+    let tiles = (
+        region.width.div_ceil(tile_size),
+        region.height.div_ceil(tile_size),
+    );
+    let mut tile_order = (0..tiles.1)
+        .cartesian_product(0..tiles.0)
+        .map(|(y, x)| (x, y))
+        .collect_vec();
+
+    match order {
+        TileOrder::Shuffled => tile_order.shuffle(rng),
+        TileOrder::Scanline => {}
+        TileOrder::Hilbert => {
+            let n = tiles.0.max(tiles.1).next_power_of_two();
+            tile_order.sort_by_key(|&(x, y)| hilbert_index(n, x, y));
+        }
+        TileOrder::Spiral => {
+            // Rings of tiles around the centre, each one walked round by angle:
+            let centre = (tiles.0 as f32 / 2.0, tiles.1 as f32 / 2.0);
+            let key = |&(x, y): &(u32, u32)| {
+                let (dx, dy) = (x as f32 + 0.5 - centre.0, y as f32 + 0.5 - centre.1);
+                (dx.abs().max(dy.abs()).round() as u32, dy.atan2(dx))
+            };
+            tile_order.sort_by(|a, b| {
+                let (a, b) = (key(a), key(b));
+                a.0.cmp(&b.0).then(a.1.total_cmp(&b.1))
+            });
+        }
+    }
+
+    tile_order
+        .into_iter()
+        .flat_map(|(tx, ty)| {
+            let xs = (tx * tile_size)..((tx + 1) * tile_size).min(region.width);
+            let ys = (ty * tile_size)..((ty + 1) * tile_size).min(region.height);
+            let (xs, ys) = (
+                (region.x + xs.start)..(region.x + xs.end),
+                (region.y + ys.start)..(region.y + ys.end),
+            );
+            let mut tile = ys
+                .cartesian_product(xs)
+                .map(|(y, x)| SampleSource {
+                    screen_pos: [x as f32 / dims.0 as f32, y as f32 / dims.1 as f32],
+                    out_pos: [x, y],
+                    samples: 0,
+                    flags: 0,
+                })
+                .collect_vec();
+            if order == TileOrder::Shuffled {
+                tile.shuffle(rng);
+            }
+            tile
+        })
+        .collect()
+}
+
+// Distance along the Hilbert curve filling an n by n grid, n a power of two.
+fn hilbert_index(n: u32, mut x: u32, mut y: u32) -> u64 {
+    let mut d = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as u32;
+        let ry = (y & s > 0) as u32;
+        d += s as u64 * s as u64 * ((3 * rx) ^ ry) as u64;
+        // Rotate the quadrant so the curve inside it lines up:
+        if ry == 0 {
+            if rx == 1 {
+                x = n - 1 - x;
+                y = n - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    d
+}
+
 impl PathtracerState {
-    pub fn new(device: &wgpu::Device, dims: (u32, u32), threads: u32) -> Self {
-        let mut rng = rand::rng();
+    pub fn new(device: &wgpu::Device, pathtracer: &Pathtracer) -> Self {
+        let dims = pathtracer.dims;
+        let threads = pathtracer.threads;
+        let mut rng = match pathtracer.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_rng(&mut rand::rng()),
+        };
         let samples: Vec<_> = (0..=threads).map(|_| Sample::zeroed()).collect();
 
         let random_states: Vec<_> = (0..=threads)
@@ -158,42 +300,47 @@ impl PathtracerState {
 
         dbg!(dims);
         dbg!(threads);
-        let dims_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Dims Buffer"),
-            contents: bytemuck::cast_slice(&[dims.0, dims.1]),
+        let settings_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Pathtracer Settings Buffer"),
+            contents: bytemuck::bytes_of(&PathtracerSettings {
+                dims: [dims.0, dims.1],
+                max_bounces: pathtracer.max_bounces,
+                adaptive_threshold: pathtracer.adaptive_threshold.unwrap_or(0.0),
+                min_samples: pathtracer.min_samples,
+                _pad: [0; 3],
+            }),
             usage: wgpu::BufferUsages::UNIFORM,
         });
 
+        let accumulation_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Accumulation Buffer"),
+            contents: bytemuck::bytes_of(&Accumulation::default()),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let focus_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Focus Buffer"),
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            size: std::mem::size_of::<f32>() as u64,
+            mapped_at_creation: false,
+        });
+
         // Sampling buffers:
         let sampling_counter_buffer =
             device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: Some("Sample Counter Buffer"),
-                usage: wgpu::BufferUsages::STORAGE,
-                contents: bytemuck::bytes_of(&[0u32, 0u32]),
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+                contents: bytemuck::bytes_of(&[0u32; 3]),
             });
 
-        let tile_size = 128;
-        let mut data = (0..dims.0 / tile_size)
-            .cartesian_product(0..dims.1 / tile_size)
-            .collect_vec();
-
-        data.shuffle(&mut rand::rng());
-
-        let mut data = data
-            .into_iter()
-            .flat_map(|(x, y)| {
-                ((x * tile_size)..(x * tile_size + tile_size))
-                    .cartesian_product((y * tile_size)..(y * tile_size + tile_size))
-                    .map(|(x, y)| SampleSource {
-                        screen_pos: [x as f32 / dims.0 as f32, y as f32 / dims.1 as f32],
-                        out_pos: [x, y],
-                        samples: 0,
-                        flags: 0,
-                    })
-            })
-            .collect_vec();
-        data.shuffle(&mut rand::rng());
-        // data.sort_by_key(|d| (d.out_pos[0] / 256, d.out_pos[1] / 256));
+        let region = pathtracer.region().mirrored(dims.0);
+        let data = sample_sources(
+            dims,
+            region,
+            pathtracer.tile_size,
+            pathtracer.tile_order,
+            &mut rng,
+        );
 
         let sampling_source_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Sample Data Buffer"),
@@ -208,10 +355,11 @@ impl PathtracerState {
             mapped_at_creation: false,
         });
 
+        // Luminance since the last convergence check and the running estimate, see sampleConverge:
         let sampling_std_buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("Sample Std Buffer"),
             usage: wgpu::BufferUsages::STORAGE,
-            size: ((dims.0 * dims.1) as u64 * std::mem::size_of::<[f32; 4]>() as u64),
+            size: ((dims.0 * dims.1) as u64 * std::mem::size_of::<[[f32; 4]; 2]>() as u64),
             mapped_at_creation: false,
         });
 
@@ -242,6 +390,36 @@ impl PathtracerState {
             },
             count: None,
         });
+        bgles.push(wgpu::BindGroupLayoutEntry {
+            binding: 19,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: false },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        });
+        bgles.push(wgpu::BindGroupLayoutEntry {
+            binding: 20,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        });
+        bgles.push(wgpu::BindGroupLayoutEntry {
+            binding: 21,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: false },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        });
         let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
             label: Some("Pathtracer State Bind Group Layout"),
             entries: &bgles,
@@ -329,7 +507,21 @@ impl PathtracerState {
                 },
                 wgpu::BindGroupEntry {
                     binding: 18,
-                    resource: dims_buffer.as_entire_binding(),
+                    resource: settings_buffer.as_entire_binding(),
+                },
+                // Next event estimation:
+                wgpu::BindGroupEntry {
+                    binding: 19,
+                    resource: shadow_data_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 20,
+                    resource: accumulation_buffer.as_entire_binding(),
+                },
+                // Autofocus:
+                wgpu::BindGroupEntry {
+                    binding: 21,
+                    resource: focus_buffer.as_entire_binding(),
                 },
             ],
         });
@@ -343,6 +535,11 @@ impl PathtracerState {
             sampling_data_buffer: sampling_source_buffer,
             sampling_mean_buffer: sampling_sum_buffer,
             sampling_std_buffer,
+            accumulation: Accumulation::default(),
+            accumulation_buffer,
+            focus_buffer,
+            scene_epoch: 0,
+            frame: 0,
             new_ray_queue: terminate_queue,
             extension_queue,
             shadow_queue: connect_queue,
@@ -351,4 +548,147 @@ impl PathtracerState {
             bind_group,
         }
     }
+
+    // Starts a new epoch, the next dispatch clears the accumulated samples before anything else.
+    pub fn reset_accumulation(&mut self) {
+        self.accumulation.epoch = self.accumulation.epoch.wrapping_add(1);
+        self.accumulation.reset = 1;
+    }
+
+    // Uploads the epoch for the next dispatch, only the first one after a reset clears.
+    pub fn write_accumulation(&mut self, queue: &wgpu::Queue) {
+        queue.write_buffer(
+            &self.accumulation_buffer,
+            0,
+            bytemuck::bytes_of(&self.accumulation),
+        );
+        self.accumulation.reset = 0;
+    }
+
+    // Total number of samples handed out by spawnSample since accumulation was last reset.
+    pub fn samples_spawned(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> anyhow::Result<u64> {
+        // The first counter is which pixel is next, which also counts the converged ones skipped.
+        // After it is the spawn count as two halves:
+        let bytes = read_buffer(device, queue, &self.sampling_counter_buffer, 12)?;
+        let low = u32::from_le_bytes(bytes[4..8].try_into()?);
+        let high = u32::from_le_bytes(bytes[8..12].try_into()?);
+        Ok(((high as u64) << 32) | low as u64)
+    }
+
+    // Distance found by the last autofocus pass, 0 if it didn't hit anything.
+    pub fn focus_distance(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> anyhow::Result<f32> {
+        let bytes = read_buffer(device, queue, &self.focus_buffer, 4)?;
+        Ok(f32::from_le_bytes(bytes[0..4].try_into()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Checks every pixel of the region is handed out exactly once, and nothing outside it.
+    fn assert_covers(dims: (u32, u32), region: Crop, tile_size: u32, order: TileOrder) {
+        let sources = sample_sources(
+            dims,
+            region,
+            tile_size,
+            order,
+            &mut StdRng::seed_from_u64(0),
+        );
+        let mut seen = vec![0u8; (dims.0 * dims.1) as usize];
+        for s in sources.iter() {
+            let [x, y] = s.out_pos;
+            assert!(
+                region.contains(x, y),
+                "{order:?} {dims:?} tile {tile_size}: {x},{y} is outside the region"
+            );
+            assert_eq!(
+                s.screen_pos,
+                [x as f32 / dims.0 as f32, y as f32 / dims.1 as f32]
+            );
+            seen[(y * dims.0 + x) as usize] += 1;
+        }
+        assert_eq!(sources.len(), region.area() as usize);
+        assert!(
+            seen.iter().enumerate().all(|(i, &n)| {
+                let (x, y) = (i as u32 % dims.0, i as u32 / dims.0);
+                n == region.contains(x, y) as u8
+            }),
+            "{order:?} {dims:?} tile {tile_size}: some pixels were missed or repeated"
+        );
+    }
+
+    #[test]
+    fn tiles_cover_every_pixel_once() {
+        // Sizes that don't divide into whole tiles, grids of tiles that aren't powers of two
+        // (1920x1080 is 15 by 9 tiles of 128), a tile bigger than the image and single pixels:
+        let cases = [
+            ((1920, 1080), 128),
+            ((1000, 7), 64),
+            ((37, 100), 16),
+            ((5, 3), 8),
+            ((33, 17), 1),
+        ];
+        for order in TileOrder::value_variants() {
+            for (dims, tile_size) in cases {
+                let region = Crop {
+                    x: 0,
+                    y: 0,
+                    width: dims.0,
+                    height: dims.1,
+                };
+                assert_covers(dims, region, tile_size, *order);
+            }
+        }
+    }
+
+    #[test]
+    fn tiles_cover_a_crop_once() {
+        let crops = [
+            (0, 0, 1, 1),
+            (1, 1, 1918, 1078),
+            (1000, 500, 300, 77),
+            (1919, 0, 1, 1080),
+        ];
+        for order in TileOrder::value_variants() {
+            for (x, y, width, height) in crops {
+                let region = Crop {
+                    x,
+                    y,
+                    width,
+                    height,
+                };
+                assert_covers((1920, 1080), region, 128, *order);
+            }
+        }
+    }
+
+    #[test]
+    fn hilbert_visits_neighbours() {
+        for n in [1, 2, 4, 8, 32] {
+            let mut cells = (0..n)
+                .cartesian_product(0..n)
+                .map(|(x, y)| (hilbert_index(n, x, y), x, y))
+                .collect_vec();
+            cells.sort();
+            for (i, (d, _, _)) in cells.iter().enumerate() {
+                assert_eq!(*d, i as u64, "n {n}: the curve skips or repeats a cell");
+            }
+            for (a, b) in cells.iter().tuple_windows() {
+                assert_eq!(
+                    a.1.abs_diff(b.1) + a.2.abs_diff(b.2),
+                    1,
+                    "n {n}: {a:?} and {b:?} aren't neighbours"
+                );
+            }
+        }
+    }
 }
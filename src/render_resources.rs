@@ -65,6 +65,7 @@ fn setup_renderer(mut commands: Commands, window: Option<Res<WinitWindow>>) {
     let required_features = wgpu::Features::empty()
         .union(wgpu::Features::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING)
         .union(wgpu::Features::BUFFER_BINDING_ARRAY)
+        .union(wgpu::Features::TEXTURE_BINDING_ARRAY)
         .union(wgpu::Features::STORAGE_RESOURCE_BINDING_ARRAY);
 
     let (device, queue) = rt
@@ -114,3 +115,39 @@ fn setup_renderer(mut commands: Commands, window: Option<Res<WinitWindow>>) {
     commands.insert_resource(RenderQueue(Arc::new(queue)));
     commands.insert_resource(RenderDevice(Arc::new(device)));
 }
+
+// Copies `size` bytes from the start of `buffer` into a staging buffer and blocks until they
+// can be read back on the CPU. The source buffer needs COPY_SRC usage.
+pub fn read_buffer(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    buffer: &wgpu::Buffer,
+    size: u64,
+) -> anyhow::Result<Vec<u8>> {
+    let staging = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("Readback Staging Buffer"),
+        size,
+        usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("Readback Encoder"),
+    });
+    encoder.copy_buffer_to_buffer(buffer, 0, &staging, 0, size);
+    queue.submit([encoder.finish()]);
+
+    let (tx, rx) = crossbeam::channel::bounded(1);
+    staging
+        .slice(..)
+        .map_async(wgpu::MapMode::Read, move |result| {
+            tx.send(result).ok();
+        });
+    device.poll(wgpu::PollType::wait_indefinitely())?;
+    rx.recv()??;
+
+    let data = staging.slice(..).get_mapped_range().to_vec();
+    staging.unmap();
+
+    Ok(data)
+}
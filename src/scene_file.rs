@@ -0,0 +1,219 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use bevy_ecs::prelude::*;
+use glam::Vec3;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    camera::{Lens, ProjectionSettings},
+    display::Tonemapper,
+    gltf_loader,
+    material::{Material, MaterialServer},
+    mesh::{MeshDescriptor, MeshServer, Normalise},
+    obj_loader,
+    pathtracer::Crop,
+    pathtracer_state::TileOrder,
+    sky::Sky,
+    transform::Transform,
+};
+
+// A scene described in json rather than a startup system, see scenes/ for examples.
+// Paths inside it are relative to the working directory, same as on the command line.
+#[derive(Resource, Serialize, Deserialize, Default, Debug, Clone)]
+#[serde(default)]
+pub struct SceneFile {
+    pub settings: RenderSettings,
+    pub camera: Option<SceneCamera>,
+    pub meshes: HashMap<String, MeshDescriptor>,
+    pub materials: HashMap<String, Material>,
+    pub instances: Vec<SceneInstance>,
+    // Whole gltf scenes to spawn alongside the instances:
+    pub gltf: Vec<String>,
+    // Every model in each obj, with the materials from its mtl:
+    pub obj: Vec<SceneObj>,
+}
+
+// Anything also given on the command line is overridden by it.
+#[derive(Serialize, Deserialize, Default, Debug, Clone)]
+#[serde(default)]
+pub struct RenderSettings {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub threads: Option<u32>,
+    pub samples: Option<u32>,
+    pub max_bounces: Option<u32>,
+    pub seed: Option<u64>,
+    pub adaptive_threshold: Option<f32>,
+    pub min_samples: Option<u32>,
+    pub tile_size: Option<u32>,
+    pub tile_order: Option<TileOrder>,
+    pub crop: Option<Crop>,
+    pub environment: Option<PathBuf>,
+    pub environment_rotation: Option<f32>,
+    pub environment_intensity: Option<f32>,
+    // A physical sun and sky, used when there's no environment map:
+    pub sky: Option<Sky>,
+    pub tonemapper: Option<Tonemapper>,
+    pub exposure: Option<f32>,
+    pub auto_exposure: bool,
+    pub white_point: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SceneCamera {
+    pub position: Vec3,
+    pub forward: Vec3,
+    pub up: Vec3,
+    pub focal_length: f32,
+    #[serde(flatten)]
+    pub lens: Lens,
+    #[serde(flatten)]
+    pub projection: ProjectionSettings,
+}
+
+impl Default for SceneCamera {
+    fn default() -> Self {
+        Self {
+            position: Vec3::ZERO,
+            forward: Vec3::Z,
+            up: Vec3::Y,
+            focal_length: 1.0,
+            lens: Lens::default(),
+            projection: ProjectionSettings::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SceneInstance {
+    pub mesh: String,
+    pub material: String,
+    #[serde(default)]
+    pub transform: SceneTransform,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SceneObj {
+    pub path: String,
+    #[serde(default)]
+    pub transform: SceneTransform,
+    #[serde(default)]
+    pub normalise: Normalise,
+}
+
+// Rotation is euler angles in degrees, applied in the same order as Transform.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SceneTransform {
+    pub scale: Vec3,
+    pub rotation: Vec3,
+    pub translation: Vec3,
+}
+
+impl Default for SceneTransform {
+    fn default() -> Self {
+        Self {
+            scale: Vec3::ONE,
+            rotation: Vec3::ZERO,
+            translation: Vec3::ZERO,
+        }
+    }
+}
+
+impl From<&SceneTransform> for Transform {
+    fn from(t: &SceneTransform) -> Self {
+        Transform {
+            scale: t.scale.extend(0.0),
+            rotation: t.rotation.map(f32::to_radians).extend(0.0),
+            translation: t.translation.extend(1.0),
+        }
+    }
+}
+
+impl SceneFile {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open scene {}", path.display()))?;
+        serde_json::from_reader(std::io::BufReader::new(file))
+            .with_context(|| format!("Failed to parse scene {}", path.display()))
+    }
+
+    // Every file the scene refers to has to be there, so a typo is an error up front rather than
+    // something the loaders trip over later.
+    pub fn check_assets(&self) -> anyhow::Result<()> {
+        let meshes = self
+            .meshes
+            .values()
+            .filter_map(|descriptor| match descriptor {
+                MeshDescriptor::TOBJ { path, .. } | MeshDescriptor::GLTF { path, .. } => Some(path),
+                MeshDescriptor::Rect | MeshDescriptor::Cube => None,
+            });
+        let paths = self
+            .gltf
+            .iter()
+            .chain(self.obj.iter().map(|obj| &obj.path))
+            .chain(meshes);
+        for path in paths {
+            std::fs::metadata(path).with_context(|| format!("Failed to open {}", path))?;
+        }
+        Ok(())
+    }
+}
+
+pub fn spawn_scene_file(
+    mut commands: Commands,
+    mut mesh_server: ResMut<MeshServer>,
+    mut material_server: ResMut<MaterialServer>,
+    scene: Res<SceneFile>,
+) {
+    // The paths were checked when the scene was loaded, anything still wrong with the files
+    // only loses that part of the scene:
+    for path in scene.gltf.iter() {
+        if let Err(e) = gltf_loader::load_gltf(Path::new(path), &mut mesh_server) {
+            tracing::warn!("Skipping {}: {:#}", path, e);
+        }
+    }
+
+    for obj in scene.obj.iter() {
+        if let Err(e) = obj_loader::load_obj(
+            Path::new(&obj.path),
+            &mut mesh_server,
+            Transform::from(&obj.transform),
+            obj.normalise,
+        ) {
+            tracing::warn!("Skipping {}: {:#}", obj.path, e);
+        }
+    }
+
+    let meshes: HashMap<_, _> = scene
+        .meshes
+        .iter()
+        .map(|(name, descriptor)| (name, mesh_server.load_mesh(descriptor.clone())))
+        .collect();
+    let materials: HashMap<_, _> = scene
+        .materials
+        .iter()
+        .map(|(name, material)| (name, material_server.add_material(*material)))
+        .collect();
+
+    for instance in scene.instances.iter() {
+        let Some(mesh) = meshes.get(&instance.mesh) else {
+            tracing::warn!("Skipping instance of unknown mesh {}", instance.mesh);
+            continue;
+        };
+        let Some(material) = materials.get(&instance.material) else {
+            tracing::warn!(
+                "Skipping instance of unknown material {}",
+                instance.material
+            );
+            continue;
+        };
+
+        commands.spawn((Transform::from(&instance.transform), *mesh, *material));
+    }
+}
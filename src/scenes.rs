@@ -2,8 +2,12 @@ use std::f32;
 
 use crate::{
     app::BevyApp,
+    cli::Args,
+    gltf_loader,
     material::{Material, MaterialServer},
-    mesh::{MeshDescriptor, MeshServer},
+    mesh::{MeshDescriptor, MeshServer, Normalise},
+    obj_loader,
+    scene_file::{self, SceneFile},
     schedule,
     transform::Transform,
 };
@@ -12,9 +16,13 @@ use bevy_ecs::prelude::*;
 use glam::{Vec3, Vec4};
 
 pub fn initialize(app: &mut BevyApp) {
-    app.world
-        .get_resource_or_init::<Schedules>()
-        .add_systems(schedule::Startup, simple_scene);
+    app.world.get_resource_or_init::<Schedules>().add_systems(
+        schedule::Startup,
+        (
+            simple_scene.run_if(not(resource_exists::<SceneFile>)),
+            scene_file::spawn_scene_file.run_if(resource_exists::<SceneFile>),
+        ),
+    );
 }
 
 fn spawn_cornell(
@@ -137,10 +145,21 @@ fn simple_scene(
     mut commands: Commands,
     mut mesh_server: ResMut<MeshServer>,
     mut material_server: ResMut<MaterialServer>,
+    args: Res<Args>,
 ) {
+    if let Some(path) = args.scene.as_ref().filter(|p| {
+        p.extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("gltf") || e.eq_ignore_ascii_case("glb"))
+    }) {
+        if let Err(e) = gltf_loader::load_gltf(path, &mut mesh_server) {
+            tracing::warn!("Skipping {}: {:#}", path.display(), e);
+        }
+        return;
+    }
+
     let cube_mesh = mesh_server.load_mesh(MeshDescriptor::Cube);
     // let rect_mesh = mesh_server.load_mesh(MeshDescriptor::Rect);
-    let dragon_mesh = mesh_server.load_mesh(MeshDescriptor::TOBJ("./assets/dragon.obj".to_owned()));
+
     let gold_material = material_server.add_material(Material {
         colour: Vec4::new(1.0, 0.99, 0.0, 1.0),
         metallic: 0.0,
@@ -170,12 +189,31 @@ fn simple_scene(
         Vec3::ONE * 3.0,
         Vec3::new(0.0, 0.0, 3.0),
     );
+
+    let dragon_transform = Transform {
+        scale: Vec4::ONE,
+        rotation: Vec4::ZERO,
+        translation: Vec4::new(0.0, -0.89, 2.75, 0.0),
+    };
+
+    // An obj given on the command line takes the dragon's place in the box, with its own
+    // materials, fitted to the same size as the dragon:
+    if let Some(path) = args.scene.as_ref() {
+        if let Err(e) =
+            obj_loader::load_obj(path, &mut mesh_server, dragon_transform, Normalise::Uniform)
+        {
+            tracing::warn!("Skipping {}: {:#}", path.display(), e);
+        }
+        return;
+    }
+
+    let dragon_mesh = mesh_server.load_mesh(MeshDescriptor::TOBJ {
+        path: "./assets/dragon.obj".to_owned(),
+        model: 0,
+        normalise: Normalise::Uniform,
+    });
     commands.spawn((
-        Transform {
-            scale: Vec4::ONE,
-            rotation: Vec4::ZERO,
-            translation: Vec4::new(0.0, -0.89, 2.75, 0.0),
-        },
+        dragon_transform,
         // gold_material,
         glass_material,
         // cube_mesh,
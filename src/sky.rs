@@ -0,0 +1,203 @@
+use std::f32::consts::PI;
+
+use glam::{Mat3, Vec3};
+use serde::{Deserialize, Serialize};
+
+use crate::environment::Sun;
+
+// Angular radius of the sun disk in radians.
+const SUN_RADIUS: f32 = 0.00465;
+
+// Luminance of the sun before the atmosphere dims it, in the same kcd/m² as the sky.
+const SUN_LUMINANCE: f32 = 2.0e6;
+
+// Brings kcd/m² down to something the default exposure shows well.
+const SCALE: f32 = 0.4;
+
+// Daylight from Preetham, Shirley and Smits, "A Practical Analytic Model for Daylight" (1999).
+// The sky is baked into an environment map, so it's looked up and importance sampled the same as
+// an image would be. The sun disk is too small for that and is sampled as a light of its own.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct Sky {
+    // Degrees above the horizon, and about y from +x towards +z:
+    pub sun_elevation: f32,
+    pub sun_azimuth: f32,
+    // Haziness, 2 is a very clear sky and 10 is hazy:
+    pub turbidity: f32,
+    // How much of the sun and sky the grey ground below the horizon reflects:
+    pub ground_albedo: f32,
+}
+
+impl Default for Sky {
+    fn default() -> Self {
+        Self {
+            sun_elevation: 45.0,
+            sun_azimuth: 90.0,
+            turbidity: 3.0,
+            ground_albedo: 0.3,
+        }
+    }
+}
+
+impl Sky {
+    pub fn sun_direction(&self) -> Vec3 {
+        let (e, a) = (
+            self.sun_elevation.to_radians(),
+            self.sun_azimuth.to_radians(),
+        );
+        Vec3::new(e.cos() * a.cos(), e.sin(), e.cos() * a.sin())
+    }
+
+    // The model falls apart past the horizon, so the sky is kept as it is at sunset:
+    fn sun_theta(&self) -> f32 {
+        (PI / 2.0 - self.sun_elevation.to_radians()).clamp(0.0, PI / 2.0 - 0.01)
+    }
+
+    // Perez et al's luminance distribution over the sky, for the angle from the zenith and the
+    // angle to the sun.
+    fn perez(c: [f32; 5], theta: f32, gamma: f32) -> f32 {
+        (1.0 + c[0] * (c[1] / theta.cos().max(0.01)).exp())
+            * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * gamma.cos().powi(2))
+    }
+
+    // Sky radiance in linear rgb for a direction above the horizon.
+    pub fn sky_radiance(&self, dir: Vec3) -> Vec3 {
+        let t = self.turbidity;
+        let ts = self.sun_theta();
+        let sun = Vec3::new(
+            ts.sin() * self.sun_azimuth.to_radians().cos(),
+            ts.cos(),
+            ts.sin() * self.sun_azimuth.to_radians().sin(),
+        );
+
+        let luminance = [
+            0.1787 * t - 1.4630,
+            -0.3554 * t + 0.4275,
+            -0.0227 * t + 5.3251,
+            0.1206 * t - 2.5771,
+            -0.0670 * t + 0.3703,
+        ];
+        let x = [
+            -0.0193 * t - 0.2592,
+            -0.0665 * t + 0.0008,
+            -0.0004 * t + 0.2125,
+            -0.0641 * t - 0.8989,
+            -0.0033 * t + 0.0452,
+        ];
+        let y = [
+            -0.0167 * t - 0.2608,
+            -0.0950 * t + 0.0092,
+            -0.0079 * t + 0.2102,
+            -0.0441 * t - 1.6537,
+            -0.0109 * t + 0.0529,
+        ];
+
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * ts);
+        let zenith_luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let (ts2, ts3) = (ts * ts, ts * ts * ts);
+        let zenith_x = t * t * (0.00166 * ts3 - 0.00375 * ts2 + 0.00209 * ts)
+            + t * (-0.02903 * ts3 + 0.06377 * ts2 - 0.03202 * ts + 0.00394)
+            + (0.11693 * ts3 - 0.21196 * ts2 + 0.06052 * ts + 0.25886);
+        let zenith_y = t * t * (0.00275 * ts3 - 0.00610 * ts2 + 0.00317 * ts)
+            + t * (-0.04214 * ts3 + 0.08970 * ts2 - 0.04153 * ts + 0.00516)
+            + (0.15346 * ts3 - 0.26756 * ts2 + 0.06670 * ts + 0.26688);
+
+        let theta = dir.y.clamp(0.0, 1.0).acos();
+        let gamma = dir.dot(sun).clamp(-1.0, 1.0).acos();
+        let relative = |c| Self::perez(c, theta, gamma) / Self::perez(c, 0.0, ts);
+
+        xyy_to_rgb(
+            zenith_x * relative(x),
+            zenith_y * relative(y),
+            zenith_luminance.max(0.0) * relative(luminance),
+        ) * SCALE
+    }
+
+    // Radiance of the sun disk once the atmosphere has scattered some of it away.
+    pub fn sun_radiance(&self) -> Vec3 {
+        if self.sun_elevation <= 0.0 {
+            return Vec3::ZERO;
+        }
+
+        // Kasten and Young's relative air mass:
+        let zenith = 90.0 - self.sun_elevation;
+        let mass = 1.0 / (zenith.to_radians().cos() + 0.50572 * (96.07995 - zenith).powf(-1.6364));
+
+        // Rayleigh and Angstrom aerosol optical depths at red, green and blue wavelengths in um:
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let depth = Vec3::new(0.68, 0.55, 0.44)
+            .to_array()
+            .map(|l| 0.008735 * l.powf(-4.08) + beta * l.powf(-1.3));
+        (-Vec3::from(depth) * mass).exp() * SUN_LUMINANCE * SCALE
+    }
+
+    // The sun disk as a light of its own, see Environment::with_sun.
+    pub fn sun(&self) -> Sun {
+        Sun {
+            direction: self.sun_direction(),
+            cos_radius: SUN_RADIUS.cos(),
+            // 2pi(1 - cos), without losing it all to rounding for such a small disk:
+            solid_angle: 4.0 * PI * (SUN_RADIUS / 2.0).sin().powi(2),
+            radiance: self.sun_radiance(),
+        }
+    }
+
+    // Radiance for every pixel of a width by height environment map, in the layout
+    // Environment expects. Just the sky and the ground, without the sun.
+    pub fn bake(&self, width: u32, height: u32) -> Vec<Vec3> {
+        let (w, h) = (width as usize, height as usize);
+        let direction = |i: usize| {
+            let phi = 2.0 * PI * ((i % w) as f32 + 0.5) / width as f32;
+            let theta = PI * ((i / w) as f32 + 0.5) / height as f32;
+            Vec3::new(
+                theta.sin() * phi.cos(),
+                theta.cos(),
+                theta.sin() * phi.sin(),
+            )
+        };
+        let solid_angle = |i: usize| {
+            let theta = PI * ((i / w) as f32 + 0.5) / height as f32;
+            2.0 * PI * PI * theta.sin() / (w * h) as f32
+        };
+
+        let mut radiance = (0..w * h)
+            .map(|i| {
+                let dir = direction(i);
+                if dir.y >= 0.0 {
+                    self.sky_radiance(dir)
+                } else {
+                    Vec3::ZERO
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // The ground is lit by the sky above it and the sun:
+        let sky_irradiance: Vec3 = radiance
+            .iter()
+            .enumerate()
+            .map(|(i, rad)| *rad * direction(i).y.max(0.0) * solid_angle(i))
+            .sum();
+        let sun = self.sun();
+        let sun_power = sun.radiance * sun.solid_angle;
+        let irradiance = sky_irradiance + sun_power * sun.direction.y.max(0.0);
+        for (i, rad) in radiance.iter_mut().enumerate() {
+            if direction(i).y < 0.0 {
+                *rad = self.ground_albedo * irradiance / PI;
+            }
+        }
+
+        radiance
+    }
+}
+
+fn xyy_to_rgb(x: f32, y: f32, luminance: f32) -> Vec3 {
+    if y <= 0.0 {
+        return Vec3::ZERO;
+    }
+    let xyz = Vec3::new(x / y * luminance, luminance, (1.0 - x - y) / y * luminance);
+    let to_rgb = Mat3::from_cols_array(&[
+        3.2406, -0.9689, 0.0557, -1.5372, 1.8758, -0.2040, -0.4986, 0.0415, 1.0570,
+    ]);
+    (to_rgb * xyz).max(Vec3::ZERO)
+}
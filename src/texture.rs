@@ -1,47 +1,210 @@
-// use anyhow::*;
-// use image::GenericImageView;
-// use tracing::error;
-
-// pub struct Texture {
-//     #[allow(unused)]
-//     pub texture: wgpu::Texture,
-//     pub view: wgpu::TextureView,
-//     pub sampler: wgpu::Sampler,
-// }
-
-// impl Texture {
-//     pub fn new(device: &wgpu::Device, dims: (u32, u32)) -> Result<Self> {
-//         let size = wgpu::Extent3d {
-//             width: dims.0,
-//             height: dims.1,
-//             depth_or_array_layers: 1,
-//         };
-//         let texture = device.create_texture(&wgpu::TextureDescriptor {
-//             label: Some("Surface Texture"),
-//             size,
-//             mip_level_count: 1,
-//             sample_count: 1,
-//             dimension: wgpu::TextureDimension::D2,
-//             format: wgpu::TextureFormat::Rgba8UnormSrgb,
-//             usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-//             view_formats: &[],
-//         });
-
-//         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-//         let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-//             address_mode_u: wgpu::AddressMode::ClampToEdge,
-//             address_mode_v: wgpu::AddressMode::ClampToEdge,
-//             address_mode_w: wgpu::AddressMode::ClampToEdge,
-//             mag_filter: wgpu::FilterMode::Nearest,
-//             min_filter: wgpu::FilterMode::Nearest,
-//             mipmap_filter: wgpu::FilterMode::Nearest,
-//             ..Default::default()
-//         });
-
-//         Ok(Self {
-//             texture,
-//             view,
-//             sampler,
-//         })
-//     }
-// }
+use std::{path::PathBuf, sync::Arc};
+
+use bevy_ecs::prelude::*;
+use crossbeam::channel::bounded;
+use glam::{Vec2, Vec4};
+
+use crate::{
+    app::BevyApp,
+    material::MaterialServer,
+    render_resources::{RenderDevice, RenderQueue},
+    schedule,
+};
+
+// Length of the texture binding array, the pipelines are built against a fixed layout so
+// unused slots are bound to a plain white texture.
+pub const MAX_TEXTURES: u32 = 256;
+
+pub fn initialize(app: &mut BevyApp) {
+    app.world.insert_resource(TextureServer::default());
+    app.world
+        .get_resource_or_init::<Schedules>()
+        .add_systems(schedule::Update, texture_loading_system);
+}
+
+// Where the image behind a texture slot comes from.
+#[derive(Clone)]
+pub enum TextureSource {
+    Path(PathBuf),
+    // Still encoded, as it was stored in the file it's embedded in:
+    Embedded { label: String, bytes: Arc<[u8]> },
+}
+
+pub struct TextureLoading {
+    slot: usize,
+    rx: crossbeam::channel::Receiver<Option<image::RgbaImage>>,
+}
+
+// Loads the images behind the materials' texture slots in the background, the same way the
+// MeshServer loads meshes. Images are kept as they are stored, so colour and emissive textures
+// are decoded from srgb where they're sampled and metallic/roughness stays linear.
+#[derive(Resource, Default)]
+pub struct TextureServer {
+    loading: Vec<TextureLoading>,
+    // Indexed by texture slot - 1, None until loaded or if it failed to:
+    images: Vec<Option<Arc<image::RgbaImage>>>,
+    views: Vec<wgpu::TextureView>,
+    sampler: Option<wgpu::Sampler>,
+}
+
+pub fn texture_loading_system(
+    mut texture_server: ResMut<TextureServer>,
+    material_server: Res<MaterialServer>,
+    device: Option<Res<RenderDevice>>,
+    queue: Option<Res<RenderQueue>>,
+) {
+    let TextureServer {
+        loading, images, ..
+    } = texture_server.bypass_change_detection();
+
+    // Start on any textures the materials have picked up since:
+    for source in material_server.textures().iter().skip(images.len()) {
+        if images.len() == MAX_TEXTURES as usize {
+            tracing::warn!("Only the first {} textures will be bound", MAX_TEXTURES);
+        }
+        loading.push(TextureLoading::start(images.len(), source.clone()));
+        images.push(None);
+    }
+
+    let mut arrived = Vec::new();
+    loading.retain(|l| match l.rx.try_recv() {
+        Ok(image) => {
+            images[l.slot] = image.map(Arc::new);
+            arrived.push(l.slot);
+            false
+        }
+        Err(_) => true,
+    });
+
+    if let (Some(device), Some(queue)) = (device, queue) {
+        if texture_server.sampler.is_none() {
+            texture_server.create_views(&device.0, &queue.0);
+        } else {
+            // Only the new ones, everything else is already up:
+            for slot in arrived.iter().copied() {
+                texture_server.upload_slot(&device.0, &queue.0, slot);
+            }
+        }
+    }
+    if !arrived.is_empty() {
+        texture_server.set_changed();
+    }
+}
+
+impl TextureLoading {
+    fn start(slot: usize, source: TextureSource) -> Self {
+        let (tx, rx) = bounded(1);
+        rayon::spawn(move || {
+            let image = match &source {
+                TextureSource::Path(path) => image::open(path).inspect_err(|e| {
+                    tracing::warn!("Failed to load texture {}: {}", path.display(), e)
+                }),
+                TextureSource::Embedded { label, bytes } => image::load_from_memory(bytes)
+                    .inspect_err(|e| tracing::warn!("Failed to load texture {}: {}", label, e)),
+            }
+            .map(|i| i.into_rgba8())
+            .ok();
+            tx.send(image).expect("Expected to send texture");
+        });
+        Self { slot, rx }
+    }
+}
+
+impl TextureServer {
+    pub fn is_loading(&self) -> bool {
+        !self.loading.is_empty()
+    }
+
+    // Views for every slot of the binding array, white where there's nothing loaded.
+    pub fn views(&self) -> &[wgpu::TextureView] {
+        &self.views
+    }
+
+    pub fn sampler(&self) -> Option<&wgpu::Sampler> {
+        self.sampler.as_ref()
+    }
+
+    // Fills every slot once there's a device, with whatever has loaded before it did.
+    fn create_views(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let white = image::RgbaImage::from_pixel(1, 1, image::Rgba([255; 4]));
+        let white = upload(device, queue, &white, "White Texture");
+        self.views = vec![white; MAX_TEXTURES as usize];
+        for slot in 0..self.images.len() {
+            self.upload_slot(device, queue, slot);
+        }
+
+        self.sampler = Some(device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Material Texture Sampler"),
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            address_mode_w: wgpu::AddressMode::Repeat,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        }));
+    }
+
+    fn upload_slot(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, slot: usize) {
+        if let (Some(Some(image)), Some(view)) = (self.images.get(slot), self.views.get_mut(slot)) {
+            *view = upload(device, queue, image, "Material Texture");
+        }
+    }
+
+    // Bilinear and repeating like the sampler, in 0..1, or white for an empty or missing slot.
+    pub fn sample(&self, slot: u32, uv: Vec2) -> Vec4 {
+        let Some(Some(image)) = slot
+            .checked_sub(1)
+            .and_then(|i| self.images.get(i as usize))
+        else {
+            return Vec4::ONE;
+        };
+
+        let (w, h) = image.dimensions();
+        let p = uv * Vec2::new(w as f32, h as f32) - 0.5;
+        let f = p - p.floor();
+        let texel = |dx: i64, dy: i64| {
+            let x = (p.x.floor() as i64 + dx).rem_euclid(w as i64) as u32;
+            let y = (p.y.floor() as i64 + dy).rem_euclid(h as i64) as u32;
+            Vec4::from(image.get_pixel(x, y).0.map(|c| c as f32 / 255.0))
+        };
+
+        let top = texel(0, 0).lerp(texel(1, 0), f.x);
+        let bottom = texel(0, 1).lerp(texel(1, 1), f.x);
+        top.lerp(bottom, f.y)
+    }
+}
+
+fn upload(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    image: &image::RgbaImage,
+    label: &str,
+) -> wgpu::TextureView {
+    let size = wgpu::Extent3d {
+        width: image.width(),
+        height: image.height(),
+        depth_or_array_layers: 1,
+    };
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba8Unorm,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    });
+    queue.write_texture(
+        texture.as_image_copy(),
+        image.as_raw(),
+        wgpu::TexelCopyBufferLayout {
+            offset: 0,
+            bytes_per_row: Some(4 * image.width()),
+            rows_per_image: Some(image.height()),
+        },
+        size,
+    );
+    texture.create_view(&wgpu::TextureViewDescriptor::default())
+}
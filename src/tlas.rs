@@ -1,5 +1,4 @@
 use bevy_ecs::resource::Resource;
-use glam::Mat4;
 use glam::UVec3;
 use glam::Vec3;
 use glam::Vec4Swizzles;
@@ -13,6 +12,7 @@ use crate::bvh::AABBGPU;
 use crate::bvh::BVH;
 use crate::bvh::BVHNode;
 use crate::bvh::BVHNodeGPU;
+use crate::bvh::BuildMethod;
 use crate::instance::Instance;
 use crate::mesh::Mesh;
 use crate::transform::Transform;
@@ -22,6 +22,8 @@ pub struct TLAS {
     pub nodes: Vec<BVHNode>,
     pub instance_ids: Vec<usize>,
     pub aabbs: Vec<AABB>,
+    // SAH cost when the tree was built, refits are compared against it:
+    pub build_cost: f32,
 }
 
 impl BVH for TLAS {
@@ -55,48 +57,55 @@ impl BVH for TLAS {
     fn node_bounds(&self, idx: usize) -> AABB {
         self.nodes[idx].bounds
     }
+
+    fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+}
+
+// World space bounds of each instance's geometry.
+fn instance_aabbs(
+    aabbs: &Vec<AABB>,
+    transforms: &Vec<Transform>,
+    instances: &Vec<Instance>,
+) -> Vec<AABB> {
+    instances
+        .iter()
+        .map(|i| {
+            let aabb = aabbs[i.geometry_idx as usize];
+            let corners = repeat_n((0..=1).into_iter(), 3)
+                .multi_cartesian_product()
+                .map(|p| {
+                    let [x, y, z] = p.try_into().unwrap();
+                    Vec3::new(
+                        if x == 0 { aabb.lb.x } else { aabb.ub.x },
+                        if y == 0 { aabb.lb.y } else { aabb.ub.y },
+                        if z == 0 { aabb.lb.z } else { aabb.ub.z },
+                    )
+                })
+                .collect_vec();
+
+            let m = transforms[i.transform_idx as usize].matrix();
+
+            let aabb = corners
+                .iter()
+                .map(|c| m.mul_vec4(c.extend(1.0)).xyz())
+                .map(|c| AABB { lb: c, ub: c })
+                .reduce(|acc, aabb| acc.union(&aabb))
+                .unwrap();
+            aabb
+        })
+        .collect_vec()
 }
 
 impl TLAS {
-    pub fn new(aabbs: &Vec<AABB>, transforms: &Vec<Transform>, instances: &Vec<Instance>) -> Self {
-        let aabbs = instances
-            .iter()
-            .map(|i| {
-                let aabb = aabbs[i.geometry_idx as usize];
-                let corners = repeat_n((0..=1).into_iter(), 3)
-                    .multi_cartesian_product()
-                    .map(|p| {
-                        let [x, y, z] = p.try_into().unwrap();
-                        Vec3::new(
-                            if x == 0 { aabb.lb.x } else { aabb.ub.x },
-                            if y == 0 { aabb.lb.y } else { aabb.ub.y },
-                            if z == 0 { aabb.lb.z } else { aabb.ub.z },
-                        )
-                    })
-                    .collect_vec();
-
-                let transform = &transforms[i.transform_idx as usize];
-
-                let translate = Mat4::from_translation(transform.translation.truncate());
-
-                let rotate = Mat4::from_rotation_x(transform.rotation.x).mul_mat4(
-                    &Mat4::from_rotation_y(transform.rotation.y)
-                        .mul_mat4(&Mat4::from_rotation_z(transform.rotation.z)),
-                );
-
-                let scale = Mat4::from_scale(transform.scale.truncate());
-
-                let m = translate.mul_mat4(&rotate.mul_mat4(&scale));
-
-                let aabb = corners
-                    .iter()
-                    .map(|c| m.mul_vec4(c.extend(1.0)).xyz())
-                    .map(|c| AABB { lb: c, ub: c })
-                    .reduce(|acc, aabb| acc.union(&aabb))
-                    .unwrap();
-                aabb
-            })
-            .collect_vec();
+    pub fn new(
+        aabbs: &Vec<AABB>,
+        transforms: &Vec<Transform>,
+        instances: &Vec<Instance>,
+        method: &BuildMethod,
+    ) -> Self {
+        let aabbs = instance_aabbs(aabbs, transforms, instances);
 
         let aabbs2 = aabbs.clone();
         let mut bvh = TLAS {
@@ -109,6 +118,7 @@ impl TLAS {
             }],
             instance_ids: (0..instances.len() as usize).collect(),
             aabbs,
+            build_cost: 0.0,
         };
 
         // println!("AHHH:");
@@ -127,7 +137,13 @@ impl TLAS {
         //     }
         // }
         // println!("-------");
-        bvh.initialize(1);
+        bvh.build_cost = bvh.initialize(method);
+        tracing::debug!(
+            "Built TLAS for {} instances, {} nodes, SAH cost {:.2}",
+            instances.len(),
+            bvh.nodes.len(),
+            bvh.build_cost
+        );
         // for i in 0..bvh.instance_ids.len() {
         //     println!(
         //         "i: {} id: {}, lb: {}, ub: {}",
@@ -156,6 +172,141 @@ impl TLAS {
 
         bvh
     }
+
+    // Moves the instances without changing the tree, which is much cheaper than a rebuild but
+    // gets worse the further they move from where they were built. Expects the same instances
+    // in the same order as when it was built, and returns the new SAH cost.
+    pub fn refit(
+        &mut self,
+        aabbs: &Vec<AABB>,
+        transforms: &Vec<Transform>,
+        instances: &Vec<Instance>,
+        method: &BuildMethod,
+    ) -> f32 {
+        let world = instance_aabbs(aabbs, transforms, instances);
+        for (aabb, id) in self.aabbs.iter_mut().zip(self.instance_ids.iter()) {
+            *aabb = world[*id];
+        }
+
+        BVH::refit(self);
+        self.sah_cost(&method.sah_settings())
+    }
+
+    // Whether a refit to this cost has got bad enough to be worth building again. A lone leaf
+    // (a single instance, or ones the builder couldn't split) is as good as it gets however it
+    // moves, and its cost is 0 when it has no area, so that's never compared against.
+    pub fn needs_rebuild(&self, cost: f32, rebuild_ratio: f32) -> bool {
+        self.nodes.len() > 1 && cost > self.build_cost * rebuild_ratio
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::{Vec3, Vec4};
+
+    use super::*;
+    use crate::bvh::SAHSettings;
+
+    fn unit_cube() -> Vec<AABB> {
+        vec![AABB {
+            lb: Vec3::splat(-0.5),
+            ub: Vec3::splat(0.5),
+        }]
+    }
+
+    fn at(positions: &[Vec3]) -> (Vec<Transform>, Vec<Instance>) {
+        let transforms = positions
+            .iter()
+            .map(|p| Transform {
+                scale: Vec4::ONE,
+                rotation: Vec4::ZERO,
+                translation: p.extend(1.0),
+            })
+            .collect();
+        let instances = (0..positions.len() as u32)
+            .map(|i| Instance {
+                transform_idx: i,
+                geometry_idx: 0,
+                material_idx: 0,
+            })
+            .collect();
+        (transforms, instances)
+    }
+
+    // Union of the world bounds of every instance under the node, straight from the instances.
+    fn expected_bounds(tlas: &TLAS, world: &[AABB], idx: usize) -> AABB {
+        let node = tlas.nodes[idx];
+        if node.is_leaf {
+            (node.start..node.end)
+                .map(|i| world[tlas.instance_ids[i]])
+                .fold(AABB::EMPTY, |acc, b| acc.union(&b))
+        } else {
+            expected_bounds(tlas, world, node.left).union(&expected_bounds(tlas, world, node.right))
+        }
+    }
+
+    #[test]
+    fn refit_matches_moved_instances() {
+        let method = BuildMethod::SAH(SAHSettings::default());
+        let grid = (0..64)
+            .map(|i| Vec3::new((i % 8) as f32, 0.0, (i / 8) as f32) * 3.0)
+            .collect::<Vec<_>>();
+        let (transforms, instances) = at(&grid);
+        let mut tlas = TLAS::new(&unit_cube(), &transforms, &instances, &method);
+        assert!(tlas.nodes.len() > 1);
+
+        // Nudged a little each, which the tree built for the grid still suits:
+        let nudged = grid
+            .iter()
+            .enumerate()
+            .map(|(i, p)| *p + Vec3::new((i % 3) as f32, (i % 5) as f32, (i % 7) as f32) * 0.1)
+            .collect::<Vec<_>>();
+        let (transforms, instances) = at(&nudged);
+        let cost = tlas.refit(&unit_cube(), &transforms, &instances, &method);
+
+        let world = instance_aabbs(&unit_cube(), &transforms, &instances);
+        for idx in 0..tlas.nodes.len() {
+            let (bounds, expected) = (tlas.nodes[idx].bounds, expected_bounds(&tlas, &world, idx));
+            assert_eq!(
+                (bounds.lb, bounds.ub),
+                (expected.lb, expected.ub),
+                "node {idx}"
+            );
+        }
+        let fresh = TLAS::new(&unit_cube(), &transforms, &instances, &method);
+        assert_eq!(tlas.nodes[0].bounds.lb, fresh.nodes[0].bounds.lb);
+        assert_eq!(tlas.nodes[0].bounds.ub, fresh.nodes[0].bounds.ub);
+        assert!(!tlas.needs_rebuild(cost, 1.5));
+
+        // Every instance sent somewhere else on the grid, so the leaves now span all of it:
+        let scrambled = (0..grid.len())
+            .map(|i| grid[i * 27 % 64])
+            .collect::<Vec<_>>();
+        let (transforms, instances) = at(&scrambled);
+        let cost = tlas.refit(&unit_cube(), &transforms, &instances, &method);
+        let ratio = cost / tlas.build_cost;
+        assert!(ratio > 1.5, "refit cost only grew by {ratio}");
+        assert!(tlas.needs_rebuild(cost, 1.5));
+        assert!(tlas.needs_rebuild(cost, ratio * 0.99));
+        assert!(!tlas.needs_rebuild(cost, ratio * 1.01));
+    }
+
+    #[test]
+    fn lone_instance_never_rebuilds() {
+        let method = BuildMethod::SAH(SAHSettings::default());
+        // Flattened to a point, so there's no area for the cost to come from:
+        let point = vec![AABB::default()];
+        let (transforms, instances) = at(&[Vec3::ZERO]);
+        let mut tlas = TLAS::new(&point, &transforms, &instances, &method);
+        assert_eq!(tlas.build_cost, 0.0);
+
+        for p in [Vec3::X, Vec3::new(10.0, -3.0, 2.0)] {
+            let (transforms, instances) = at(&[p]);
+            let cost = tlas.refit(&unit_cube(), &transforms, &instances, &method);
+            assert!(cost > 0.0);
+            assert!(!tlas.needs_rebuild(cost, 1.5));
+        }
+    }
 }
 
 // pub struct TLASData {
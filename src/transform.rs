@@ -1,5 +1,5 @@
 use bevy_ecs::component::Component;
-use glam::Vec4;
+use glam::{EulerRot, Mat4, Quat, Vec4};
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable, Default, Component)]
@@ -8,3 +8,32 @@ pub struct Transform {
     pub rotation: Vec4,
     pub translation: Vec4,
 }
+
+impl Transform {
+    // Same composition as Transform.matrix() in common.slang: translate * rotate_xyz * scale.
+    pub fn matrix(&self) -> Mat4 {
+        let rotation = Quat::from_euler(
+            EulerRot::XYZ,
+            self.rotation.x,
+            self.rotation.y,
+            self.rotation.z,
+        );
+        Mat4::from_scale_rotation_translation(
+            self.scale.truncate(),
+            rotation,
+            self.translation.truncate(),
+        )
+    }
+
+    // Decomposes an affine matrix, any shear (from non-uniform scale under a rotated parent)
+    // is lost as there is nowhere to put it.
+    pub fn from_matrix(m: Mat4) -> Self {
+        let (scale, rotation, translation) = m.to_scale_rotation_translation();
+        let (x, y, z) = rotation.to_euler(EulerRot::XYZ);
+        Self {
+            scale: scale.extend(0.0),
+            rotation: Vec4::new(x, y, z, 0.0),
+            translation: translation.extend(1.0),
+        }
+    }
+}